@@ -0,0 +1,586 @@
+use std::{
+    io::{BufRead, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config, docker::PlannedChange, error::Error, reconciler::SyncReason};
+
+/// Size the audit log may grow to before it is rotated, used when the config
+/// doesn't set `max_bytes`.
+pub const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Rotated audit logs kept next to the current one, used when the config
+/// doesn't set `max_files`.
+pub const DEFAULT_MAX_FILES: u32 = 5;
+
+/// Length in bytes of the key values are hashed with.
+const KEY_LEN: usize = 32;
+
+/// One applied service update. Values only appear as their HMAC-SHA256
+/// under the install's audit key, stored next to the log in `<path>.key`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// RFC 3339 in UTC, e.g. `2024-03-01T12:00:00Z`.
+    pub timestamp: String,
+    pub watcher: String,
+    pub service: String,
+    /// Why the sync ran, e.g. `secrets update` or `reconcile`.
+    pub trigger: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sync_id: Option<String>,
+    pub added: Vec<AddedKey>,
+    pub changed: Vec<ChangedKey>,
+    pub removed: Vec<RemovedKey>,
+    /// How the rolling update ended: `converged`, `failed`, or
+    /// `in_progress` when doppler-swarm shut down first.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rollout: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddedKey {
+    pub key: String,
+    pub new_hash: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangedKey {
+    pub key: String,
+    pub old_hash: String,
+    pub new_hash: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemovedKey {
+    pub key: String,
+    pub old_hash: String,
+}
+
+impl AuditEntry {
+    pub fn new(
+        watcher: &str,
+        service: &str,
+        trigger: SyncReason,
+        changes: &[PlannedChange],
+        at: SystemTime,
+        key: &[u8],
+    ) -> Self {
+        let mut entry = Self {
+            timestamp: humantime::format_rfc3339_seconds(at).to_string(),
+            watcher: watcher.to_owned(),
+            service: service.to_owned(),
+            trigger: trigger.to_string(),
+            sync_id: crate::logging::sync_id(),
+            added: vec![],
+            changed: vec![],
+            removed: vec![],
+            rollout: None,
+        };
+
+        for change in changes {
+            match change {
+                PlannedChange::Add { name, value } => entry.added.push(AddedKey {
+                    key: name.clone(),
+                    new_hash: value.keyed_hash(key),
+                }),
+                PlannedChange::Change {
+                    name,
+                    old_value,
+                    new_value,
+                } => entry.changed.push(ChangedKey {
+                    key: name.clone(),
+                    old_hash: old_value.keyed_hash(key),
+                    new_hash: new_value.keyed_hash(key),
+                }),
+                PlannedChange::Remove { name, old_value } => entry.removed.push(RemovedKey {
+                    key: name.clone(),
+                    old_hash: old_value.keyed_hash(key),
+                }),
+            }
+        }
+
+        entry
+    }
+
+    fn time(&self) -> Option<SystemTime> {
+        humantime::parse_rfc3339_weak(&self.timestamp).ok()
+    }
+}
+
+/// Which entries `doppler-swarm audit` prints. Unset fields match anything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditQuery {
+    pub service: Option<String>,
+    pub since: Option<SystemTime>,
+    pub until: Option<SystemTime>,
+}
+
+impl AuditQuery {
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        if self
+            .service
+            .as_ref()
+            .is_some_and(|service| *service != entry.service)
+        {
+            return false;
+        }
+
+        if self.since.is_none() && self.until.is_none() {
+            return true;
+        }
+
+        let Some(at) = entry.time() else {
+            return false;
+        };
+
+        self.since.is_none_or(|since| at >= since) && self.until.is_none_or(|until| at < until)
+    }
+}
+
+/// Parses `--since`/`--until`: an RFC 3339 timestamp or a plain date, which
+/// means midnight UTC.
+pub fn parse_time(value: &str) -> crate::result::Result<SystemTime> {
+    let timestamp = if value.len() == 10 {
+        format!("{value}T00:00:00Z")
+    } else {
+        value.to_owned()
+    };
+
+    humantime::parse_rfc3339_weak(&timestamp).map_err(|e| {
+        Error::config(format!(
+            "invalid time {value}, expected e.g. 2024-03-01 or 2024-03-01T12:00:00Z"
+        ))
+        .with_source(e)
+    })
+}
+
+/// Appends entries to the audit log, shared by every reconciler. The file is
+/// rotated to `<path>.1`, `<path>.2`, ... once it would grow past
+/// `max_bytes`.
+#[derive(Clone)]
+pub struct Auditor {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: u32,
+    key: Arc<[u8; KEY_LEN]>,
+    lock: Arc<Mutex<()>>,
+}
+
+impl std::fmt::Debug for Auditor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Auditor")
+            .field("path", &self.path)
+            .field("max_bytes", &self.max_bytes)
+            .field("max_files", &self.max_files)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Auditor {
+    /// Reads the audit key from `<path>.key`, creating it on first use.
+    pub fn new(config: &config::AuditLog) -> crate::result::Result<Self> {
+        let path = PathBuf::from(&config.path);
+        let key = load_or_create_key(&key_path(&path))?;
+
+        Ok(Self {
+            path,
+            max_bytes: config.max_bytes.unwrap_or(DEFAULT_MAX_BYTES),
+            max_files: config.max_files.unwrap_or(DEFAULT_MAX_FILES),
+            key: Arc::new(key),
+            lock: Arc::default(),
+        })
+    }
+
+    /// An entry for an update of `service`, with values hashed under the
+    /// audit key.
+    pub fn entry(
+        &self,
+        watcher: &str,
+        service: &str,
+        trigger: SyncReason,
+        changes: &[PlannedChange],
+        at: SystemTime,
+    ) -> AuditEntry {
+        AuditEntry::new(watcher, service, trigger, changes, at, &self.key[..])
+    }
+
+    /// Writes one JSON line and flushes it to disk before returning.
+    pub fn append(&self, entry: &AuditEntry) -> crate::result::Result<()> {
+        let mut line = serde_json::to_string(entry)
+            .map_err(|e| Error::internal("Failed to encode audit entry").with_source(e))?;
+        line.push('\n');
+
+        let _guard = self.lock.lock().unwrap();
+
+        let size = std::fs::metadata(&self.path).map_or(0, |metadata| metadata.len());
+        if size > 0 && size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| self.error("Failed to open", e))?;
+        file.write_all(line.as_bytes())
+            .and_then(|()| file.sync_data())
+            .map_err(|e| self.error("Failed to write", e))
+    }
+
+    /// Every entry matching `query`, oldest first, rotated files included.
+    #[cfg(test)]
+    pub fn query(&self, query: &AuditQuery) -> crate::result::Result<Vec<AuditEntry>> {
+        read_entries(&self.path, query)
+    }
+
+    #[cfg(test)]
+    fn files(&self) -> Vec<PathBuf> {
+        log_files(&self.path)
+    }
+
+    fn rotate(&self) -> crate::result::Result<()> {
+        if self.max_files == 0 {
+            return std::fs::remove_file(&self.path).map_err(|e| self.error("Failed to rotate", e));
+        }
+
+        for n in (1..self.max_files).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                std::fs::rename(&from, self.rotated(n + 1))
+                    .map_err(|e| self.error("Failed to rotate", e))?;
+            }
+        }
+
+        std::fs::rename(&self.path, self.rotated(1)).map_err(|e| self.error("Failed to rotate", e))
+    }
+
+    fn rotated(&self, n: u32) -> PathBuf {
+        rotated_path(&self.path, n)
+    }
+
+    fn error(&self, action: &str, e: std::io::Error) -> Error {
+        log_error(&self.path, action, e)
+    }
+}
+
+/// Reads the log without the key, so querying never creates one.
+fn read_entries(path: &Path, query: &AuditQuery) -> crate::result::Result<Vec<AuditEntry>> {
+    let mut entries = vec![];
+
+    for file_path in log_files(path) {
+        let file = match std::fs::File::open(&file_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(log_error(path, "Failed to open", e)),
+        };
+
+        for line in std::io::BufReader::new(file).lines() {
+            let line = line.map_err(|e| log_error(path, "Failed to read", e))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str::<AuditEntry>(&line) {
+                Ok(entry) if query.matches(&entry) => entries.push(entry),
+                Ok(_) => {}
+                Err(e) => log::warn!("Skipping unreadable line in {}: {e}", file_path.display()),
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// The current file and its rotations, oldest first.
+fn log_files(path: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = (1..)
+        .map(|n| rotated_path(path, n))
+        .take_while(|path| path.exists())
+        .collect();
+    files.reverse();
+    files.push(path.to_owned());
+    files
+}
+
+fn rotated_path(path: &Path, n: u32) -> PathBuf {
+    let mut path = path.to_owned().into_os_string();
+    path.push(format!(".{n}"));
+    path.into()
+}
+
+fn log_error(path: &Path, action: &str, e: std::io::Error) -> Error {
+    Error::internal(format!("{action} audit log {}", path.display())).with_source(e)
+}
+
+fn key_path(path: &Path) -> PathBuf {
+    let mut path = path.to_owned().into_os_string();
+    path.push(".key");
+    path.into()
+}
+
+/// The key is only readable by its owner. Keeping it means hashes of the same
+/// value stay comparable across restarts. A new key is written to a temporary
+/// file first, so a crash can't leave a short one behind.
+fn load_or_create_key(path: &Path) -> crate::result::Result<[u8; KEY_LEN]> {
+    let error = |action: &str, e: std::io::Error| {
+        Error::config(format!("Failed to {action} audit key {}", path.display())).with_source(e)
+    };
+
+    match std::fs::read(path) {
+        Ok(bytes) => match <[u8; KEY_LEN]>::try_from(bytes.as_slice()) {
+            Ok(key) => return Ok(key),
+            Err(_) => log::warn!(
+                "Audit key {} has {} bytes instead of {}, replacing it",
+                path.display(),
+                bytes.len(),
+                KEY_LEN
+            ),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(error("read", e)),
+    }
+
+    let mut key = [0u8; KEY_LEN];
+    getrandom::getrandom(&mut key)
+        .map_err(|e| Error::internal("Failed to generate audit key").with_source(e))?;
+
+    let mut temporary = path.to_owned().into_os_string();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    // The mode only applies to new files.
+    let _ = std::fs::remove_file(&temporary);
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temporary)
+        .and_then(|mut file| {
+            file.write_all(&key)?;
+            file.sync_all()
+        })
+        .and_then(|()| std::fs::rename(&temporary, path))
+        .map_err(|e| error("write", e))?;
+
+    Ok(key)
+}
+
+/// Prints the entries matching `query` as JSON lines, for `doppler-swarm
+/// audit`.
+pub fn print(config: &config::Config, query: &AuditQuery) -> crate::result::Result<()> {
+    let Some(audit_log) = &config.audit_log else {
+        return Err(Error::config(
+            "Configuration error: audit_log is not configured",
+        ));
+    };
+
+    let mut stdout = std::io::stdout().lock();
+    for entry in read_entries(Path::new(&audit_log.path), query)? {
+        let line = serde_json::to_string(&entry)
+            .map_err(|e| Error::internal("Failed to encode audit entry").with_source(e))?;
+        writeln!(stdout, "{line}")
+            .map_err(|e| Error::internal("Failed to print audit entry").with_source(e))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{secrets::SecretValue, testing::temp_auditor};
+    use std::time::Duration;
+
+    fn auditor(max_bytes: u64, max_files: u32) -> Auditor {
+        temp_auditor(Some(max_bytes), Some(max_files))
+    }
+
+    fn entry(service: &str, at: SystemTime) -> AuditEntry {
+        AuditEntry::new("prod", service, SyncReason::SecretsUpdate, &[], at, b"key")
+    }
+
+    fn at(timestamp: &str) -> SystemTime {
+        parse_time(timestamp).unwrap()
+    }
+
+    #[test]
+    fn test_entry_hashes_values() {
+        let auditor = auditor(DEFAULT_MAX_BYTES, DEFAULT_MAX_FILES);
+        let changes = [
+            PlannedChange::Add {
+                name: "A".to_owned(),
+                value: SecretValue::from("new-a"),
+            },
+            PlannedChange::Change {
+                name: "B".to_owned(),
+                old_value: SecretValue::from("old-b"),
+                new_value: SecretValue::from("new-b"),
+            },
+            PlannedChange::Remove {
+                name: "C".to_owned(),
+                old_value: SecretValue::from("old-c"),
+            },
+        ];
+        let entry = |auditor: &Auditor| {
+            auditor.entry(
+                "prod",
+                "backend",
+                SyncReason::SecretsUpdate,
+                &changes,
+                at("2024-03-01T12:00:00Z"),
+            )
+        };
+
+        let first = entry(&auditor);
+        assert_eq!(first.timestamp, "2024-03-01T12:00:00Z");
+        assert_eq!(first.trigger, "secrets update");
+        assert_eq!(first.added[0].key, "A");
+        assert_eq!(
+            first.changed[0].old_hash,
+            SecretValue::from("old-b").keyed_hash(&auditor.key[..])
+        );
+        assert_eq!(first.changed[0].old_hash.len(), 64);
+        assert_eq!(first.removed[0].key, "C");
+
+        let line = serde_json::to_string(&first).unwrap();
+        for plaintext in ["new-a", "old-b", "new-b", "old-c"] {
+            assert!(!line.contains(plaintext), "{line}");
+        }
+
+        // The key survives a restart, but differs between installs.
+        let reopened = Auditor::new(&config::AuditLog {
+            path: auditor.path.to_string_lossy().into_owned(),
+            max_bytes: None,
+            max_files: None,
+        })
+        .unwrap();
+        assert_eq!(entry(&reopened), first);
+        let other = self::auditor(DEFAULT_MAX_BYTES, DEFAULT_MAX_FILES);
+        assert_ne!(entry(&other).changed, first.changed);
+    }
+
+    #[test]
+    fn test_short_key_is_replaced() {
+        let auditor = auditor(DEFAULT_MAX_BYTES, DEFAULT_MAX_FILES);
+        let key_path = key_path(&auditor.path);
+        // Left behind by a crash while the key was written.
+        std::fs::write(&key_path, b"short").unwrap();
+
+        let reopened = Auditor::new(&config::AuditLog {
+            path: auditor.path.to_string_lossy().into_owned(),
+            max_bytes: None,
+            max_files: None,
+        })
+        .unwrap();
+
+        assert_eq!(std::fs::read(&key_path).unwrap(), reopened.key[..]);
+        assert_ne!(reopened.key, auditor.key);
+    }
+
+    #[test]
+    fn test_query_by_service_and_time() {
+        let auditor = auditor(DEFAULT_MAX_BYTES, DEFAULT_MAX_FILES);
+        auditor
+            .append(&entry("backend", at("2024-03-01T12:00:00Z")))
+            .unwrap();
+        auditor
+            .append(&entry("worker", at("2024-03-02T12:00:00Z")))
+            .unwrap();
+        auditor
+            .append(&entry("backend", at("2024-03-03T12:00:00Z")))
+            .unwrap();
+
+        let timestamps = |query: AuditQuery| -> Vec<String> {
+            let entries = auditor.query(&query).unwrap();
+            entries.into_iter().map(|entry| entry.timestamp).collect()
+        };
+
+        assert_eq!(timestamps(AuditQuery::default()).len(), 3);
+        assert_eq!(
+            timestamps(AuditQuery {
+                service: Some("backend".to_owned()),
+                ..Default::default()
+            }),
+            ["2024-03-01T12:00:00Z", "2024-03-03T12:00:00Z"]
+        );
+        assert_eq!(
+            timestamps(AuditQuery {
+                since: Some(at("2024-03-02")),
+                until: Some(at("2024-03-03")),
+                ..Default::default()
+            }),
+            ["2024-03-02T12:00:00Z"]
+        );
+    }
+
+    #[test]
+    fn test_query_does_not_create_key() {
+        let auditor = auditor(DEFAULT_MAX_BYTES, DEFAULT_MAX_FILES);
+        auditor
+            .append(&entry("web", SystemTime::UNIX_EPOCH))
+            .unwrap();
+        std::fs::remove_file(key_path(&auditor.path)).unwrap();
+
+        let entries = read_entries(&auditor.path, &AuditQuery::default()).unwrap();
+
+        assert_eq!(entries.len(), 1);
+        assert!(!key_path(&auditor.path).exists());
+    }
+
+    #[test]
+    fn test_rotation_keeps_max_files() {
+        let line_len = serde_json::to_string(&entry("backend", SystemTime::UNIX_EPOCH))
+            .unwrap()
+            .len() as u64
+            + 1;
+        // Two entries per file, at most two rotated files.
+        let auditor = auditor(line_len * 2, 2);
+        let start = at("2024-03-01T00:00:00Z");
+
+        for hour in 0..7 {
+            auditor
+                .append(&entry("backend", start + Duration::from_secs(hour * 3600)))
+                .unwrap();
+        }
+
+        let files = auditor.files();
+        assert_eq!(files.len(), 3);
+        assert!(!auditor.rotated(3).exists());
+
+        // The oldest entries were rotated out, the rest read back in order.
+        let timestamps: Vec<String> = auditor
+            .query(&AuditQuery::default())
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.timestamp)
+            .collect();
+        assert_eq!(
+            timestamps,
+            [
+                "2024-03-01T02:00:00Z",
+                "2024-03-01T03:00:00Z",
+                "2024-03-01T04:00:00Z",
+                "2024-03-01T05:00:00Z",
+                "2024-03-01T06:00:00Z",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(
+            parse_time("2024-03-01").unwrap(),
+            parse_time("2024-03-01T00:00:00Z").unwrap()
+        );
+        assert_eq!(
+            parse_time("yesterday").unwrap_err().to_string(),
+            "invalid time yesterday, expected e.g. 2024-03-01 or 2024-03-01T12:00:00Z: \
+             timestamp format is invalid"
+        );
+    }
+}
@@ -0,0 +1,158 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// Smallest base a server retry hint can set, so `retry: 0` can't make
+/// reconnects spin.
+const MIN_BASE: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Connected, or failing less than the breaker threshold.
+    #[default]
+    Closed,
+    /// Too many consecutive failures; still retrying at the capped delay.
+    Open,
+    /// A permanent failure (e.g. a revoked token); no more retries.
+    Halted,
+}
+
+impl std::fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Closed => "closed",
+            Self::Open => "open",
+            Self::Halted => "halted",
+        })
+    }
+}
+
+/// Exponential backoff with full jitter and a circuit breaker that opens
+/// after `threshold` consecutive failures.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    cap: Duration,
+    threshold: u32,
+    failures: u32,
+    halted: bool,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, cap: Duration, threshold: u32) -> Self {
+        Self {
+            base,
+            cap,
+            threshold,
+            failures: 0,
+            halted: false,
+        }
+    }
+
+    pub fn set_base(&mut self, base: Duration) {
+        self.base = base.max(MIN_BASE);
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn state(&self) -> CircuitState {
+        if self.halted {
+            CircuitState::Halted
+        } else if self.failures >= self.threshold {
+            CircuitState::Open
+        } else {
+            CircuitState::Closed
+        }
+    }
+
+    /// Records a failure and returns how long to wait before the next
+    /// attempt: a random delay between zero and `min(cap, base * 2^n)`.
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self.ceiling();
+        self.failures = self.failures.saturating_add(1);
+
+        ceiling.mul_f64(fastrand::f64())
+    }
+
+    /// Records a healthy connection.
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    pub fn halt(&mut self) {
+        self.halted = true;
+    }
+
+    fn ceiling(&self) -> Duration {
+        let factor = 2u32.saturating_pow(self.failures.min(31));
+        self.base.saturating_mul(factor).min(self.cap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delays_grow_up_to_cap() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10), 5);
+
+        let ceilings: Vec<u64> = (0..6)
+            .map(|_| {
+                let ceiling = backoff.ceiling();
+                assert!(backoff.next_delay() <= ceiling);
+                ceiling.as_secs()
+            })
+            .collect();
+
+        assert_eq!(ceilings, vec![1, 2, 4, 8, 10, 10]);
+    }
+
+    #[test]
+    fn test_many_failures_do_not_overflow() {
+        let mut backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(300), 10);
+
+        for _ in 0..100 {
+            assert!(backoff.next_delay() <= Duration::from_secs(300));
+        }
+    }
+
+    #[test]
+    fn test_circuit_opens_and_resets() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10), 3);
+
+        backoff.next_delay();
+        backoff.next_delay();
+        assert_eq!(backoff.state(), CircuitState::Closed);
+
+        backoff.next_delay();
+        assert_eq!(backoff.state(), CircuitState::Open);
+        assert_eq!(backoff.failures(), 3);
+
+        backoff.reset();
+        assert_eq!(backoff.state(), CircuitState::Closed);
+        assert_eq!(backoff.ceiling(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_set_base_has_a_floor() {
+        let mut backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(300), 10);
+
+        backoff.set_base(Duration::ZERO);
+        assert_eq!(backoff.ceiling(), MIN_BASE);
+
+        backoff.set_base(Duration::from_secs(10));
+        assert_eq!(backoff.ceiling(), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_halt() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10), 3);
+
+        backoff.halt();
+        assert_eq!(backoff.state(), CircuitState::Halted);
+    }
+}
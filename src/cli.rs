@@ -0,0 +1,170 @@
+use crate::{audit::AuditQuery, error::Error};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Args {
+    pub config_file: String,
+    /// Compute and log the changes without touching any service.
+    pub dry_run: bool,
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Sync and watch every configured watcher.
+    Run,
+    /// `doppler-swarm audit`: print the audit log entries matching the query.
+    Audit(AuditQuery),
+    /// `doppler-swarm notify-test`: send a test notification to every
+    /// webhook, or to `url` instead when set.
+    NotifyTest { url: Option<String> },
+}
+
+pub fn parse_args(args: impl Iterator<Item = String>) -> crate::result::Result<Args> {
+    let mut args = args.peekable();
+    let subcommand = args.next_if(|arg| arg == "audit" || arg == "notify-test");
+    let audit = subcommand.as_deref() == Some("audit");
+    let notify_test = subcommand.as_deref() == Some("notify-test");
+
+    let mut config_file = None;
+    let mut dry_run = false;
+    let mut query = AuditQuery::default();
+    let mut url = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" if subcommand.is_none() => dry_run = true,
+            "--service" if audit => query.service = Some(option_value(&arg, args.next())?),
+            "--since" if audit => {
+                query.since = Some(crate::audit::parse_time(&option_value(&arg, args.next())?)?);
+            }
+            "--until" if audit => {
+                query.until = Some(crate::audit::parse_time(&option_value(&arg, args.next())?)?);
+            }
+            "--url" if notify_test => url = Some(option_value(&arg, args.next())?),
+            flag if flag.starts_with("--") => {
+                return Err(Error::config(format!("unknown option {flag}")));
+            }
+            _ if config_file.is_some() => {
+                return Err(Error::config(format!("unexpected argument {arg}")));
+            }
+            _ => config_file = Some(arg),
+        }
+    }
+
+    Ok(Args {
+        config_file: config_file.ok_or_else(|| Error::config("no config file specified"))?,
+        dry_run,
+        command: match subcommand.as_deref() {
+            Some("audit") => Command::Audit(query),
+            Some("notify-test") => Command::NotifyTest { url },
+            _ => Command::Run,
+        },
+    })
+}
+
+fn option_value(flag: &str, value: Option<String>) -> crate::result::Result<String> {
+    value
+        .filter(|value| !value.starts_with("--"))
+        .ok_or_else(|| Error::config(format!("option {flag} needs a value")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> crate::result::Result<Args> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn error(args: &[&str]) -> String {
+        self::args(args).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_parse_args_config_file() {
+        assert_eq!(
+            args(&["config.json"]).unwrap(),
+            Args {
+                config_file: "config.json".to_owned(),
+                dry_run: false,
+                command: Command::Run,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_args_dry_run() {
+        let expected = Args {
+            config_file: "config.json".to_owned(),
+            dry_run: true,
+            command: Command::Run,
+        };
+
+        assert_eq!(args(&["--dry-run", "config.json"]).unwrap(), expected);
+        assert_eq!(args(&["config.json", "--dry-run"]).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_parse_args_audit() {
+        assert_eq!(
+            args(&[
+                "audit",
+                "--service",
+                "backend",
+                "--since",
+                "2024-03-01",
+                "config.json"
+            ])
+            .unwrap(),
+            Args {
+                config_file: "config.json".to_owned(),
+                dry_run: false,
+                command: Command::Audit(AuditQuery {
+                    service: Some("backend".to_owned()),
+                    since: Some(crate::audit::parse_time("2024-03-01T00:00:00Z").unwrap()),
+                    until: None,
+                }),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_args_notify_test() {
+        assert_eq!(
+            args(&[
+                "notify-test",
+                "--url",
+                "http://127.0.0.1:9000/hook",
+                "config.json"
+            ])
+            .unwrap()
+            .command,
+            Command::NotifyTest {
+                url: Some("http://127.0.0.1:9000/hook".to_owned())
+            }
+        );
+        assert_eq!(
+            args(&["notify-test", "config.json"]).unwrap().command,
+            Command::NotifyTest { url: None }
+        );
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert_eq!(error(&[]), "no config file specified");
+        assert_eq!(error(&["--force", "config.json"]), "unknown option --force");
+        assert_eq!(error(&["a.json", "b.json"]), "unexpected argument b.json");
+        assert_eq!(
+            error(&["audit", "--dry-run", "config.json"]),
+            "unknown option --dry-run"
+        );
+        assert_eq!(
+            error(&["audit", "config.json", "--service"]),
+            "option --service needs a value"
+        );
+        assert_eq!(
+            error(&["--service", "backend", "config.json"]),
+            "unknown option --service"
+        );
+    }
+}
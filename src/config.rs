@@ -1,56 +1,302 @@
-#[derive(Debug, Clone, serde::Deserialize)]
+use crate::error::Error;
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
 pub struct Watcher {
     pub name: String,
     pub doppler_token: String,
     pub docker_services: Vec<String>,
+    /// Log the planned changes instead of updating services.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Overrides the global Doppler API base URL for this watcher.
+    #[serde(default)]
+    pub doppler_api_url: Option<String>,
+    /// Re-sync every matched service this often to undo manual edits,
+    /// even when Doppler reports no change. Off when unset.
+    #[serde(default)]
+    pub reconcile_interval_seconds: Option<u64>,
+    /// How long to wait for more `secrets.update` events before syncing,
+    /// so a burst of dashboard edits restarts services only once.
+    #[serde(default)]
+    pub debounce_seconds: Option<u64>,
+    /// Webhooks told about every sync that changed a service or failed.
+    #[serde(default)]
+    pub notifications: Vec<Webhook>,
+    /// How long to wait for a rolling update to converge before the sync
+    /// fails.
+    #[serde(default)]
+    pub rollout_timeout_seconds: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct Webhook {
+    pub url: String,
+    #[serde(default)]
+    pub format: WebhookFormat,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookFormat {
+    /// The notification itself as a JSON object.
+    #[default]
+    Generic,
+    /// A `{"text": ...}` message for Slack incoming webhooks and compatible
+    /// chat tools.
+    Slack,
+}
+
+/// Debounce window used when a watcher doesn't set `debounce_seconds`.
+const DEFAULT_DEBOUNCE: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Rollout timeout used when a watcher doesn't set `rollout_timeout_seconds`.
+const DEFAULT_ROLLOUT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(300);
+
+impl Watcher {
+    pub fn debounce(&self) -> std::time::Duration {
+        self.debounce_seconds
+            .map(std::time::Duration::from_secs)
+            .unwrap_or(DEFAULT_DEBOUNCE)
+    }
+
+    pub fn rollout_timeout(&self) -> std::time::Duration {
+        self.rollout_timeout_seconds
+            .map(std::time::Duration::from_secs)
+            .unwrap_or(DEFAULT_ROLLOUT_TIMEOUT)
+    }
+
+    pub fn reconcile_interval(&self) -> Option<std::time::Duration> {
+        self.reconcile_interval_seconds
+            .map(std::time::Duration::from_secs)
+    }
+
+    pub fn api_url(&self) -> &str {
+        self.doppler_api_url
+            .as_deref()
+            .unwrap_or(crate::secrets::DEFAULT_DOPPLER_API_URL)
+    }
 }
 
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct Config {
     pub watchers: Vec<Watcher>,
+    /// Path to the Docker Engine API socket.
+    #[serde(default = "default_docker_socket")]
+    pub docker_socket: String,
+    /// Doppler API base URL used by watchers that don't set their own,
+    /// e.g. an egress proxy or a regional endpoint.
+    #[serde(default = "default_doppler_api_url")]
+    pub doppler_api_url: String,
+    /// Serves `/healthz`, `/readyz` and `/status` when set.
+    #[serde(default)]
+    pub status_server: Option<StatusServer>,
+    /// Appends a JSON line for every service update when set.
+    #[serde(default)]
+    pub audit_log: Option<AuditLog>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct StatusServer {
+    /// Address to listen on, e.g. `0.0.0.0:8080`.
+    pub listen: String,
+    /// `/readyz` fails once a watch stream has been silent this long.
+    #[serde(default)]
+    pub ready_stream_max_age_seconds: Option<u64>,
+}
+
+impl StatusServer {
+    pub fn ready_stream_max_age(&self) -> std::time::Duration {
+        self.ready_stream_max_age_seconds
+            .map(std::time::Duration::from_secs)
+            .unwrap_or(crate::status::DEFAULT_READY_STREAM_MAX_AGE)
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct AuditLog {
+    /// File the entries are appended to, e.g. `/var/log/doppler-swarm/audit.jsonl`.
+    pub path: String,
+    /// Rotate once the file would grow past this size.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    /// Rotated files to keep, `<path>.1` being the newest.
+    #[serde(default)]
+    pub max_files: Option<u32>,
+}
+
+fn default_docker_socket() -> String {
+    crate::engine::DEFAULT_DOCKER_SOCKET.to_owned()
+}
+
+fn default_doppler_api_url() -> String {
+    crate::secrets::DEFAULT_DOPPLER_API_URL.to_owned()
 }
 
-pub fn read_config() -> crate::result::Result<Config> {
-    let config_file = std::env::args().nth(1).ok_or("no config file specified")?;
+pub fn read_config(args: &crate::cli::Args) -> crate::result::Result<Config> {
+    let config_file = &args.config_file;
 
-    let data = std::fs::read_to_string(&config_file)
-        .map_err(|e| format!("Failed to read config file {}: {}", &config_file, e))?;
+    let data = std::fs::read_to_string(config_file).map_err(|e| {
+        Error::config(format!("Failed to read config file {config_file}")).with_source(e)
+    })?;
 
-    let config = serde_json::from_str(&data)
-        .map_err(|e| format!("Failed to parse config file {}: {}", &config_file, e))?;
+    let mut config: Config = serde_json::from_str(&data).map_err(|e| {
+        Error::config(format!("Failed to parse config file {config_file}")).with_source(e)
+    })?;
 
     validate_config(&config)?;
+    resolve_api_urls(&mut config);
+
+    if args.dry_run {
+        for watcher in &mut config.watchers {
+            watcher.dry_run = true;
+        }
+    }
 
     Ok(config)
 }
 
+/// Gives every watcher a concrete API base URL without a trailing slash.
+pub fn resolve_api_urls(config: &mut Config) {
+    for watcher in &mut config.watchers {
+        let api_url = watcher
+            .doppler_api_url
+            .get_or_insert_with(|| config.doppler_api_url.clone());
+
+        while api_url.ends_with('/') {
+            api_url.pop();
+        }
+    }
+}
+
+fn validate_api_url(api_url: &str) -> crate::result::Result<()> {
+    if !api_url.starts_with("http://") && !api_url.starts_with("https://") {
+        return Err(Error::config(format!(
+            "Configuration error: doppler API URL {} must start with http:// or https://",
+            api_url
+        )));
+    }
+
+    Ok(())
+}
+
+/// Leaves the URL out of the error: webhook URLs usually embed a token.
+pub fn validate_webhook_url(url: &str) -> crate::result::Result<()> {
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err(Error::config(
+            "Configuration error: webhook URL must start with http:// or https://",
+        ));
+    }
+
+    Ok(())
+}
+
+fn validate_status_server(status_server: &StatusServer) -> crate::result::Result<()> {
+    if status_server
+        .listen
+        .parse::<std::net::SocketAddr>()
+        .is_err()
+    {
+        return Err(Error::config(format!(
+            "Configuration error: status server address {} must be an IP address and port",
+            status_server.listen
+        )));
+    }
+
+    if status_server.ready_stream_max_age_seconds == Some(0) {
+        return Err(Error::config(
+            "Configuration error: ready stream max age must be greater than zero",
+        ));
+    }
+
+    Ok(())
+}
+
+fn validate_audit_log(audit_log: &AuditLog) -> crate::result::Result<()> {
+    if audit_log.path.is_empty() {
+        return Err(Error::config(
+            "Configuration error: audit log path cannot be empty",
+        ));
+    }
+
+    if audit_log.max_bytes == Some(0) {
+        return Err(Error::config(
+            "Configuration error: audit log max bytes must be greater than zero",
+        ));
+    }
+
+    Ok(())
+}
+
 pub fn validate_config(config: &Config) -> crate::result::Result<()> {
     let mut services_seen = vec![];
 
+    if config.docker_socket.is_empty() {
+        return Err(Error::config(
+            "Configuration error: docker socket cannot be empty",
+        ));
+    }
+
+    validate_api_url(&config.doppler_api_url)?;
+
+    if let Some(status_server) = &config.status_server {
+        validate_status_server(status_server)?;
+    }
+
+    if let Some(audit_log) = &config.audit_log {
+        validate_audit_log(audit_log)?;
+    }
+
     for watcher in &config.watchers {
         if watcher.name.is_empty() {
-            return Err("Configuration error: watcher name cannot be empty".into());
+            return Err(Error::config(
+                "Configuration error: watcher name cannot be empty",
+            ));
         }
 
         if watcher.doppler_token.is_empty() {
-            return Err("Configuration error: doppler token cannot be empty".into());
+            return Err(Error::config(
+                "Configuration error: doppler token cannot be empty",
+            ));
         }
 
         if watcher.docker_services.is_empty() {
-            return Err("Configuration error: docker services cannot be empty".into());
+            return Err(Error::config(
+                "Configuration error: docker services cannot be empty",
+            ));
+        }
+
+        if let Some(api_url) = &watcher.doppler_api_url {
+            validate_api_url(api_url)?;
+        }
+
+        for webhook in &watcher.notifications {
+            validate_webhook_url(&webhook.url)?;
+        }
+
+        if watcher.reconcile_interval_seconds == Some(0) {
+            return Err(Error::config(
+                "Configuration error: reconcile interval must be greater than zero",
+            ));
+        }
+
+        if watcher.rollout_timeout_seconds == Some(0) {
+            return Err(Error::config(
+                "Configuration error: rollout timeout must be greater than zero",
+            ));
         }
 
         for service in &watcher.docker_services {
             if service.is_empty() {
-                return Err("Configuration error: docker service name cannot be empty".into());
+                return Err(Error::config(
+                    "Configuration error: docker service name cannot be empty",
+                ));
             }
 
             if services_seen.contains(service) {
-                return Err(format!(
+                return Err(Error::config(format!(
                     "Configuration error: service {} is used in multiple watchers",
                     service
-                )
-                .into());
+                )));
             } else {
                 services_seen.push(service.to_owned());
             }
@@ -72,13 +318,19 @@ mod tests {
                     name: "watcher1".to_string(),
                     doppler_token: "token1".to_string(),
                     docker_services: vec!["service1".to_string(), "service2".to_string()],
+                    ..Default::default()
                 },
                 Watcher {
                     name: "watcher2".to_string(),
                     doppler_token: "token2".to_string(),
                     docker_services: vec!["service3".to_string()],
+                    ..Default::default()
                 },
             ],
+            docker_socket: default_docker_socket(),
+            doppler_api_url: default_doppler_api_url(),
+            status_server: None,
+            audit_log: None,
         };
 
         let result = validate_config(&config);
@@ -92,7 +344,12 @@ mod tests {
                 name: "".to_string(),
                 doppler_token: "token1".to_string(),
                 docker_services: vec!["service1".to_string()],
+                ..Default::default()
             }],
+            docker_socket: default_docker_socket(),
+            doppler_api_url: default_doppler_api_url(),
+            status_server: None,
+            audit_log: None,
         };
 
         let result = validate_config(&config);
@@ -103,6 +360,29 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_validate_config_zero_reconcile_interval() {
+        let config = Config {
+            watchers: vec![Watcher {
+                name: "watcher1".to_string(),
+                doppler_token: "token1".to_string(),
+                docker_services: vec!["service1".to_string()],
+                reconcile_interval_seconds: Some(0),
+                ..Default::default()
+            }],
+            docker_socket: default_docker_socket(),
+            doppler_api_url: default_doppler_api_url(),
+            status_server: None,
+            audit_log: None,
+        };
+
+        let result = validate_config(&config);
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "Configuration error: reconcile interval must be greater than zero"
+        );
+    }
+
     #[test]
     fn test_validate_config_empty_doppler_token() {
         let config = Config {
@@ -110,7 +390,12 @@ mod tests {
                 name: "watcher1".to_string(),
                 doppler_token: "".to_string(),
                 docker_services: vec!["service1".to_string()],
+                ..Default::default()
             }],
+            docker_socket: default_docker_socket(),
+            doppler_api_url: default_doppler_api_url(),
+            status_server: None,
+            audit_log: None,
         };
 
         let result = validate_config(&config);
@@ -128,7 +413,12 @@ mod tests {
                 name: "watcher1".to_string(),
                 doppler_token: "token1".to_string(),
                 docker_services: vec!["good-service".to_string(), "".to_string()],
+                ..Default::default()
             }],
+            docker_socket: default_docker_socket(),
+            doppler_api_url: default_doppler_api_url(),
+            status_server: None,
+            audit_log: None,
         };
 
         let result = validate_config(&config);
@@ -146,7 +436,12 @@ mod tests {
                 name: "watcher1".to_string(),
                 doppler_token: "token1".to_string(),
                 docker_services: vec![],
+                ..Default::default()
             }],
+            docker_socket: default_docker_socket(),
+            doppler_api_url: default_doppler_api_url(),
+            status_server: None,
+            audit_log: None,
         };
 
         let result = validate_config(&config);
@@ -165,13 +460,19 @@ mod tests {
                     name: "watcher1".to_string(),
                     doppler_token: "token1".to_string(),
                     docker_services: vec!["service1".to_string(), "service2".to_string()],
+                    ..Default::default()
                 },
                 Watcher {
                     name: "watcher2".to_string(),
                     doppler_token: "token2".to_string(),
                     docker_services: vec!["service1".to_string()],
+                    ..Default::default()
                 },
             ],
+            docker_socket: default_docker_socket(),
+            doppler_api_url: default_doppler_api_url(),
+            status_server: None,
+            audit_log: None,
         };
 
         let result = validate_config(&config);
@@ -181,4 +482,115 @@ mod tests {
             "Configuration error: service service1 is used in multiple watchers"
         );
     }
+
+    #[test]
+    fn test_validate_config_invalid_api_url() {
+        let config = Config {
+            watchers: vec![Watcher {
+                name: "watcher1".to_string(),
+                doppler_token: "token1".to_string(),
+                docker_services: vec!["service1".to_string()],
+                doppler_api_url: Some("localhost:8080".to_string()),
+                ..Default::default()
+            }],
+            docker_socket: default_docker_socket(),
+            doppler_api_url: default_doppler_api_url(),
+            status_server: None,
+            audit_log: None,
+        };
+
+        let result = validate_config(&config);
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "Configuration error: doppler API URL localhost:8080 must start with http:// or https://"
+        );
+    }
+
+    #[test]
+    fn test_validate_config_invalid_status_server_address() {
+        let config: Config =
+            serde_json::from_str(r#"{"watchers": [], "status_server": {"listen": "localhost"}}"#)
+                .unwrap();
+
+        assert_eq!(
+            validate_config(&config).err().unwrap().to_string(),
+            "Configuration error: status server address localhost must be an IP address and port"
+        );
+    }
+
+    #[test]
+    fn test_validate_config_zero_audit_log_max_bytes() {
+        let config: Config = serde_json::from_str(
+            r#"{"watchers": [], "audit_log": {"path": "audit.jsonl", "max_bytes": 0}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            validate_config(&config).err().unwrap().to_string(),
+            "Configuration error: audit log max bytes must be greater than zero"
+        );
+    }
+
+    #[test]
+    fn test_notifications() {
+        let config: Config = serde_json::from_str(
+            r#"{"watchers": [{"name": "a", "doppler_token": "t", "docker_services": ["s1"],
+                "notifications": [
+                    {"url": "https://hooks.slack.com/services/T/B/X", "format": "slack"},
+                    {"url": "http://127.0.0.1:9000/hook"}
+                ]}]}"#,
+        )
+        .unwrap();
+
+        assert!(validate_config(&config).is_ok());
+        assert_eq!(
+            config.watchers[0].notifications[0].format,
+            WebhookFormat::Slack
+        );
+        assert_eq!(
+            config.watchers[0].notifications[1].format,
+            WebhookFormat::Generic
+        );
+
+        let mut config = config;
+        config.watchers[0].notifications[1].url = "hooks.example.com".to_owned();
+        assert_eq!(
+            validate_config(&config).err().unwrap().to_string(),
+            "Configuration error: webhook URL must start with http:// or https://"
+        );
+    }
+
+    #[test]
+    fn test_resolve_api_urls() {
+        let mut config: Config = serde_json::from_str(
+            r#"{
+                "doppler_api_url": "https://proxy.internal/doppler/",
+                "watchers": [
+                    {"name": "a", "doppler_token": "t", "docker_services": ["s1"]},
+                    {"name": "b", "doppler_token": "t", "docker_services": ["s2"],
+                     "doppler_api_url": "http://127.0.0.1:8080"}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        resolve_api_urls(&mut config);
+
+        assert_eq!(
+            config.watchers[0].api_url(),
+            "https://proxy.internal/doppler"
+        );
+        assert_eq!(config.watchers[1].api_url(), "http://127.0.0.1:8080");
+    }
+
+    #[test]
+    fn test_default_api_url() {
+        let config: Config = serde_json::from_str(
+            r#"{"watchers": [{"name": "a", "doppler_token": "t", "docker_services": ["s1"]}]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.doppler_api_url, "https://api.doppler.com");
+        assert_eq!(config.watchers[0].api_url(), "https://api.doppler.com");
+    }
 }
@@ -1,81 +1,188 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Watcher,
+    engine::Engine,
+    error::Error,
+    secrets::{EnvVars, SecretValue},
+};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Service {
+    #[serde(rename = "ID")]
+    pub id: String,
+    pub version: Version,
+    pub spec: ServiceSpec,
+    /// Progress of the last rolling update. Right after an update it can
+    /// still describe the one before, until Swarm starts the new one.
+    #[serde(default)]
+    pub update_status: Option<UpdateStatus>,
+}
 
-use crate::config::Watcher;
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct UpdateStatus {
+    /// `updating`, `paused`, `completed`, `rollback_started`,
+    /// `rollback_paused` or `rollback_completed`.
+    #[serde(default)]
+    pub state: Option<String>,
+    #[serde(default)]
+    pub started_at: Option<String>,
+    #[serde(default)]
+    pub message: Option<String>,
+}
 
-pub async fn get_current_env_vars(
-    service_name: &str,
-) -> crate::result::Result<HashMap<String, String>> {
-    let mut child = tokio::process::Command::new("docker")
-        .arg("service")
-        .arg("inspect")
-        .arg("--format")
-        .arg("{{json .Spec.TaskTemplate.ContainerSpec.Env}}")
-        .arg(service_name)
-        .stdout(std::process::Stdio::piped())
-        .spawn()
-        .map_err(|e| format!("Failed to spawn docker service inspect command: {e}"))?;
+/// A task as listed by `/tasks`. Its spec is the service's task template at
+/// the time the task was created.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Task {
+    #[serde(default)]
+    pub spec: TaskTemplate,
+    pub status: TaskStatus,
+}
 
-    log::info!("Running \"docker service inspect --format {{json .Spec.TaskTemplate.ContainerSpec.Env}} {}\"", service_name);
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TaskStatus {
+    pub state: String,
+}
 
-    let stdout = child.stdout.take().unwrap();
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Version {
+    pub index: u64,
+}
 
-    let mut buf = Vec::new();
+/// Service label listing the env keys doppler-swarm manages, comma separated.
+/// Only these keys are ever removed from a service; env vars set by other
+/// means (e.g. in a stack file) are left alone.
+pub const MANAGED_KEYS_LABEL: &str = "doppler-swarm.managed-keys";
+
+/// Service spec as returned by the Engine API. Only the fields we touch are
+/// typed; everything else is kept verbatim so updates round-trip the spec.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ServiceSpec {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<HashMap<String, String>>,
+    #[serde(default)]
+    pub task_template: TaskTemplate,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
 
-    tokio::io::copy(&mut tokio::io::BufReader::new(stdout), &mut buf)
-        .await
-        .map_err(|_e| {
-            format!(
-                "Failed to read docker service inspect output: {}",
-                String::from_utf8_lossy(&buf)
-            )
-        })?;
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TaskTemplate {
+    #[serde(default)]
+    pub container_spec: ContainerSpec,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
 
-    let env_var_pairs: Option<Vec<String>> = serde_json::from_slice(&buf).map_err(|_e| {
-        format!(
-            "Failed to parse docker service inspect output: {}",
-            String::from_utf8_lossy(&buf)
-        )
-    })?;
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ServiceUpdateResponse {
+    #[serde(default)]
+    warnings: Option<Vec<String>>,
+}
 
-    let Some(env_var_pairs) = env_var_pairs else {
-        return Ok(HashMap::new());
+pub fn get_current_env_vars(service: &Service) -> crate::result::Result<EnvVars> {
+    let Some(env_var_pairs) = &service.spec.task_template.container_spec.env else {
+        return Ok(EnvVars::new());
     };
 
-    let mut env_vars = HashMap::new();
+    let mut env_vars = EnvVars::new();
 
     for pair in env_var_pairs {
-        let (name, value) = parse_env_pair(&pair)?;
+        let (name, value) = parse_env_pair(pair)?;
         env_vars.insert(name, value);
     }
 
     Ok(env_vars)
 }
 
-fn parse_env_pair(env_var: &str) -> crate::result::Result<(String, String)> {
+pub fn managed_keys(service: &Service) -> HashSet<String> {
+    service
+        .spec
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(MANAGED_KEYS_LABEL))
+        .map(|keys| {
+            keys.split(',')
+                .filter(|key| !key.is_empty())
+                .map(|key| key.to_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn managed_keys_label(env_vars: &EnvVars) -> String {
+    let mut keys: Vec<&str> = env_vars.keys().map(|key| key.as_str()).collect();
+    keys.sort();
+    keys.join(",")
+}
+
+pub async fn inspect_service(
+    engine: &Engine,
+    service_name: &str,
+) -> crate::result::Result<Service> {
+    log::info!(service = service_name; "Inspecting docker service");
+
+    engine
+        .get(&format!("/services/{service_name}"))
+        .await
+        .map_err(|e| Error::docker_inspect("Failed to inspect service").with_source(e))
+}
+
+fn parse_env_pair(env_var: &str) -> crate::result::Result<(String, SecretValue)> {
     match env_var.split_once('=') {
         Some((name, value)) => {
             if name.is_empty() {
-                return Err(format!("Cannot parse env var: {}", env_var).into());
+                return Err(Error::docker_inspect("Cannot parse env var: empty name"));
             }
 
             if value.is_empty() {
-                return Err(format!("Cannot parse env var: {}", env_var).into());
+                return Err(Error::docker_inspect(format!(
+                    "Cannot parse env var {}: empty value",
+                    name
+                )));
             }
 
-            Ok((name.to_owned(), value.to_owned()))
+            Ok((name.to_owned(), value.into()))
         }
-        None => Err(format!("Cannot parse env var: {env_var}").into()),
+        None => Err(Error::docker_inspect("Cannot parse env var: missing '='")),
     }
 }
 
+/// Lists env vars that were managed by us and are gone from Doppler.
+/// Keys we never managed are not touched.
 pub fn list_env_vars_to_delete(
-    old_env_vars: HashMap<String, String>,
-    new_env_vars: HashMap<String, String>,
+    old_env_vars: EnvVars,
+    new_env_vars: EnvVars,
+    managed_keys: &HashSet<String>,
 ) -> crate::result::Result<Vec<String>> {
     let mut env_vars_to_delete = vec![];
 
     for old_env_var in old_env_vars.keys() {
-        if !new_env_vars.contains_key(old_env_var) {
+        if managed_keys.contains(old_env_var) && !new_env_vars.contains_key(old_env_var) {
             env_vars_to_delete.push(old_env_var.to_owned());
         }
     }
@@ -84,10 +191,10 @@ pub fn list_env_vars_to_delete(
 }
 
 pub fn list_env_pairs_to_update(
-    old_env_vars: HashMap<String, String>,
-    new_env_vars: HashMap<String, String>,
-) -> crate::result::Result<HashMap<String, String>> {
-    let mut env_vars_to_update = HashMap::new();
+    old_env_vars: EnvVars,
+    new_env_vars: EnvVars,
+) -> crate::result::Result<EnvVars> {
+    let mut env_vars_to_update = EnvVars::new();
 
     for (new_env_var_name, new_env_var_value) in new_env_vars {
         // check that old env vars contain the new env var name
@@ -106,68 +213,337 @@ pub fn list_env_pairs_to_update(
     Ok(env_vars_to_update)
 }
 
-pub async fn update_service(
-    service_name: &str,
-    old_env_vars: HashMap<String, String>,
-    new_env_vars: HashMap<String, String>,
-) -> crate::result::Result<()> {
-    dbg!(service_name, &old_env_vars, &new_env_vars);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateOutcome {
+    Updated,
+    Unchanged,
+    /// The service changed after it was inspected; re-inspect and retry.
+    VersionConflict,
+}
 
-    let env_vars_to_delete = list_env_vars_to_delete(old_env_vars.clone(), new_env_vars.clone())?;
+/// Updates the env of an inspected service. The update carries the spec
+/// version read during inspection, so Swarm rejects it if anyone changed the
+/// service in between instead of applying our diff on top of a stale view.
+pub async fn update_service(
+    engine: &Engine,
+    service: &Service,
+    old_env_vars: EnvVars,
+    new_env_vars: EnvVars,
+) -> crate::result::Result<UpdateOutcome> {
+    let service_name = service.spec.name.as_str();
+    let label = managed_keys_label(&new_env_vars);
+
+    let env_vars_to_delete = list_env_vars_to_delete(
+        old_env_vars.clone(),
+        new_env_vars.clone(),
+        &managed_keys(service),
+    )?;
     let env_vars_to_update = list_env_pairs_to_update(old_env_vars, new_env_vars)?;
+    let label_changed = service
+        .spec
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(MANAGED_KEYS_LABEL))
+        != Some(&label);
+
+    if env_vars_to_delete.is_empty() && env_vars_to_update.is_empty() && !label_changed {
+        log::info!(service = service_name; "No changes to apply");
+        return Ok(UpdateOutcome::Unchanged);
+    }
+
+    let mut spec = service.spec.clone();
+
+    spec.labels
+        .get_or_insert_with(HashMap::new)
+        .insert(MANAGED_KEYS_LABEL.to_owned(), label);
+
+    let container_spec = &mut spec.task_template.container_spec;
+    container_spec.env = Some(apply_env_changes(
+        container_spec.env.as_deref().unwrap_or_default(),
+        &env_vars_to_delete,
+        &env_vars_to_update,
+    ));
+
+    log::info!(
+        service = service_name;
+        "Updating docker service: {}",
+        describe_env_changes(&env_vars_to_delete, &env_vars_to_update)
+    );
 
-    if env_vars_to_delete.is_empty() && env_vars_to_update.is_empty() {
-        log::info!("No changes to apply to {}", service_name);
-        return Ok(());
+    let response: ServiceUpdateResponse = match engine
+        .post(
+            &format!(
+                "/services/{}/update?version={}",
+                service.id, service.version.index
+            ),
+            &spec,
+        )
+        .await
+    {
+        Ok(response) => response,
+        Err(e) if e.is_version_conflict() => return Ok(UpdateOutcome::VersionConflict),
+        Err(e) => {
+            return Err(Error::docker_update("Failed to update docker service").with_source(e))
+        }
+    };
+
+    for warning in response.warnings.unwrap_or_default() {
+        log::warn!(service = service_name; "Docker service update warning: {}", warning);
     }
 
-    let mut command = tokio::process::Command::new("docker");
-    command.arg("service");
-    command.arg("update");
+    Ok(UpdateOutcome::Updated)
+}
 
-    let mut args_info = String::new();
+/// How often a rolling update is polled while waiting for it to converge.
+const ROLLOUT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Where a rolling update stands, judged from the service's `UpdateStatus`
+/// and its tasks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rollout {
+    /// Still going; says how far.
+    InProgress(String),
+    /// Every task that should run is running the current spec.
+    Converged,
+    /// Swarm paused the update or rolled it back.
+    Failed(String),
+}
 
-    for env_var in env_vars_to_delete {
-        command.arg("--env-rm").arg(&env_var);
-        args_info.push_str(&format!("--env-rm {} ", env_var));
+/// `previous` is the service's `UpdateStatus` before our update. While the
+/// service still reports that one, only the tasks count.
+pub fn rollout_progress(
+    service: &Service,
+    tasks: &[Task],
+    previous: Option<&UpdateStatus>,
+) -> Rollout {
+    let update_status = service
+        .update_status
+        .clone()
+        .filter(|status| Some(status) != previous)
+        .unwrap_or_default();
+    let message = update_status.message.unwrap_or_default();
+
+    match update_status.state.as_deref() {
+        Some("paused") => return Rollout::Failed(format!("paused: {message}")),
+        Some("rollback_paused") | Some("rollback_completed") => {
+            return Rollout::Failed(format!("rolled back: {message}"))
+        }
+        Some("updating") => return Rollout::InProgress("updating".to_owned()),
+        Some("rollback_started") => return Rollout::InProgress("rolling back".to_owned()),
+        // Completed, or not started yet: the tasks tell which.
+        _ => {}
     }
 
-    for (env_var_name, env_var_value) in env_vars_to_update {
-        let arg = format!("{}={}", env_var_name, env_var_value);
-        command.arg("--env-add").arg(&arg);
-        // dbg!(&env_var);
-        // println!("env_var: {}", &env_var);
-        args_info.push_str(&format!("--env-add \"{}\" ", arg));
+    let env = service.spec.task_template.container_spec.env.as_deref();
+    let converged = tasks
+        .iter()
+        .filter(|task| {
+            task.status.state == "running" && task.spec.container_spec.env.as_deref() == env
+        })
+        .count();
+
+    if converged == tasks.len() {
+        Rollout::Converged
+    } else {
+        Rollout::InProgress(format!(
+            "{converged} of {} tasks running the new spec",
+            tasks.len()
+        ))
     }
+}
 
-    args_info.pop();
+/// URL path listing the tasks that should be running for a service, with
+/// `{"service":[id],"desired-state":["running"]}` URL-encoded. Service IDs
+/// are alphanumeric and need no escaping.
+fn running_tasks_path(service_id: &str) -> String {
+    format!(
+        "/tasks?filters=%7B%22service%22%3A%5B%22{service_id}%22%5D%2C%22desired-state%22%3A%5B%22running%22%5D%7D"
+    )
+}
 
-    let mut child = command
-        .arg(service_name)
-        .stdout(std::process::Stdio::piped())
-        .spawn()
-        .map_err(|e| format!("Failed to spawn docker service inspect command: {e}"))?;
+/// Polls a service updated from `service` until its rolling update
+/// completes and every task runs the new spec. A paused or rolled back
+/// update, or one that is still going after `timeout`, is an error. Returns
+/// `Rollout::InProgress` when `stop` flips to true first.
+pub async fn wait_for_rollout(
+    engine: &Engine,
+    service: &Service,
+    service_name: &str,
+    timeout: Duration,
+    stop: &mut tokio::sync::watch::Receiver<bool>,
+) -> crate::result::Result<Rollout> {
+    let service_id = &service.id;
+    let previous = service.update_status.as_ref();
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    loop {
+        let service: Service = engine
+            .get(&format!("/services/{service_id}"))
+            .await
+            .map_err(|e| Error::docker_inspect("Failed to inspect service").with_source(e))?;
+        let tasks: Vec<Task> = engine
+            .get(&running_tasks_path(service_id))
+            .await
+            .map_err(|e| Error::docker_inspect("Failed to list service tasks").with_source(e))?;
+
+        let progress = match rollout_progress(&service, &tasks, previous) {
+            Rollout::Converged => {
+                log::info!(service = service_name; "Rolling update converged");
+                return Ok(Rollout::Converged);
+            }
+            Rollout::Failed(reason) => {
+                return Err(Error::docker_rollout(format!(
+                    "Rolling update of service {service_name} {reason}"
+                )))
+            }
+            Rollout::InProgress(progress) => progress,
+        };
 
-    log::info!(
-        "Running \"docker service update {} {}\"",
-        args_info,
-        service_name
-    );
+        let now = tokio::time::Instant::now();
+        if now >= deadline {
+            return Err(Error::docker_rollout(format!(
+                "Rolling update of service {service_name} did not converge within {}: {progress}",
+                humantime::format_duration(timeout)
+            )));
+        }
 
-    let stdout = child.stdout.take().unwrap();
+        if *stop.borrow() {
+            log::info!(
+                service = service_name;
+                "Shutting down, not waiting for the rolling update: {}",
+                progress
+            );
+            return Ok(Rollout::InProgress(progress));
+        }
+
+        log::debug!(service = service_name; "Waiting for rolling update: {}", progress);
+        // A shutdown cuts the wait short; the next poll reports it.
+        tokio::select! {
+            Ok(()) = stop.changed() => {}
+            _ = tokio::time::sleep(ROLLOUT_POLL_INTERVAL.min(deadline - now)) => {}
+        }
+    }
+}
 
-    let mut buf = Vec::new();
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlannedChange {
+    Add {
+        name: String,
+        value: SecretValue,
+    },
+    Change {
+        name: String,
+        old_value: SecretValue,
+        new_value: SecretValue,
+    },
+    Remove {
+        name: String,
+        old_value: SecretValue,
+    },
+}
 
-    tokio::io::copy(&mut tokio::io::BufReader::new(stdout), &mut buf)
-        .await
-        .map_err(|_e| {
-            format!(
-                "Failed to read docker service inspect output: {}",
-                String::from_utf8_lossy(&buf)
-            )
-        })?;
+impl PlannedChange {
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Add { name, .. } | Self::Change { name, .. } | Self::Remove { name, .. } => name,
+        }
+    }
+}
+
+impl std::fmt::Display for PlannedChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Add { name, value } => write!(f, "add {name}={value}"),
+            Self::Change {
+                name,
+                old_value,
+                new_value,
+            } => write!(f, "change {name}: {old_value} -> {new_value}"),
+            Self::Remove { name, .. } => write!(f, "remove {name}"),
+        }
+    }
+}
+
+/// Lists the env changes an update would make, ordered by key name.
+/// Values print redacted.
+pub fn plan_env_changes(
+    old_env_vars: &EnvVars,
+    new_env_vars: &EnvVars,
+    managed_keys: &HashSet<String>,
+) -> crate::result::Result<Vec<PlannedChange>> {
+    let env_vars_to_delete =
+        list_env_vars_to_delete(old_env_vars.clone(), new_env_vars.clone(), managed_keys)?;
+    let env_vars_to_update = list_env_pairs_to_update(old_env_vars.clone(), new_env_vars.clone())?;
+
+    let mut changes: Vec<PlannedChange> = env_vars_to_update
+        .into_iter()
+        .map(|(name, value)| match old_env_vars.get(&name) {
+            Some(old_value) => PlannedChange::Change {
+                name,
+                old_value: old_value.clone(),
+                new_value: value,
+            },
+            None => PlannedChange::Add { name, value },
+        })
+        .chain(env_vars_to_delete.into_iter().filter_map(|name| {
+            let old_value = old_env_vars.get(&name)?.clone();
+            Some(PlannedChange::Remove { name, old_value })
+        }))
+        .collect();
+
+    changes.sort_by(|a, b| a.name().cmp(b.name()));
+
+    Ok(changes)
+}
+
+/// Describes env changes by key name only. Values are secrets and must never
+/// end up in logs.
+pub fn describe_env_changes(env_vars_to_delete: &[String], env_vars_to_update: &EnvVars) -> String {
+    let mut removed = env_vars_to_delete.to_vec();
+    removed.sort();
 
-    Ok(())
+    let mut set: Vec<&String> = env_vars_to_update.keys().collect();
+    set.sort();
+
+    format!("set {:?}, remove {:?}", set, removed)
+}
+
+/// Applies `--env-rm`/`--env-add` style changes to a `KEY=VALUE` list,
+/// keeping the order of the entries that stay.
+pub fn apply_env_changes(
+    env: &[String],
+    env_vars_to_delete: &[String],
+    env_vars_to_update: &EnvVars,
+) -> Vec<String> {
+    let mut result: Vec<String> = env
+        .iter()
+        .filter(|pair| {
+            let name = pair.split_once('=').map_or(pair.as_str(), |(name, _)| name);
+            !env_vars_to_delete.iter().any(|key| key == name)
+                && !env_vars_to_update.contains_key(name)
+        })
+        .cloned()
+        .collect();
+
+    let mut updates: Vec<_> = env_vars_to_update.iter().collect();
+    updates.sort_by(|a, b| a.0.cmp(b.0));
+
+    for (name, value) in updates {
+        result.push(format!("{name}={}", value.expose()));
+    }
+
+    result
+}
+
+/// Whether a watcher's `docker_services` names or patterns cover `service_name`.
+pub fn matches_watcher(watcher: &Watcher, service_name: &str) -> bool {
+    watcher.docker_services.iter().any(|pattern| {
+        if is_pattern(pattern) {
+            is_match(service_name, pattern)
+        } else {
+            pattern == service_name
+        }
+    })
 }
 
 pub fn is_pattern(pattern: &str) -> bool {
@@ -205,45 +581,33 @@ pub fn is_match(text: &str, pattern: &str) -> bool {
     dp[m][n]
 }
 
-pub async fn list_services(watcher: &Watcher) -> crate::result::Result<Vec<String>> {
-    let mut child = tokio::process::Command::new("docker")
-        .arg("service")
-        .arg("ls")
-        .arg("--format")
-        .arg("{{.Name}}")
-        .stdout(std::process::Stdio::piped())
-        .spawn()
-        .map_err(|e| format!("Failed to spawn docker service ls command: {e}"))?;
-
-    log::info!("Running \"docker service ls --format {{.Name}}\"");
-
-    let stdout = child.stdout.take().unwrap();
-
-    let mut buf = Vec::new();
+pub async fn list_services(
+    engine: &Engine,
+    watcher: &Watcher,
+) -> crate::result::Result<Vec<String>> {
+    log::info!("Listing docker services");
 
-    tokio::io::copy(&mut tokio::io::BufReader::new(stdout), &mut buf)
+    let services: Vec<Service> = engine
+        .get("/services")
         .await
-        .map_err(|_e| {
-            format!(
-                "Failed to read docker service ls output: {}",
-                String::from_utf8_lossy(&buf)
-            )
-        })?;
+        .map_err(|e| Error::docker_inspect("Failed to list services").with_source(e))?;
 
-    let docker_service_names: Vec<String> = String::from_utf8_lossy(&buf)
-        .split('\n')
+    let docker_service_names: Vec<String> = services
+        .into_iter()
+        .map(|service| service.spec.name)
         .filter(|service_name| !service_name.is_empty())
-        .map(|service_name| service_name.to_owned())
         .collect();
 
     log::info!(
-        "[{}] Found {} docker services: {:?}",
-        &watcher.name,
+        watcher = watcher.name.as_str();
+        "Found {} docker services: {:?}",
         docker_service_names.len(),
         &docker_service_names
     );
 
-    match_services(watcher, docker_service_names).await
+    match_services(watcher, docker_service_names)
+        .await
+        .map_err(|e| e.context("Failed to list services"))
 }
 
 pub async fn match_services(
@@ -261,10 +625,9 @@ pub async fn match_services(
                 {
                     count += 1;
                     if services.contains(docker_service_name) {
-                        return Err(
-                            "Configuration error: service name cannot be used multiple times"
-                                .into(),
-                        );
+                        return Err(Error::config(
+                            "Configuration error: service name cannot be used multiple times",
+                        ));
                     } else {
                         services.push(docker_service_name.to_owned());
                     }
@@ -272,25 +635,23 @@ pub async fn match_services(
             }
 
             if count == 0 {
-                return Err(format!(
+                return Err(Error::config(format!(
                     "Configuration error: no services match pattern {}",
                     service_name_pattern
-                )
-                .into());
+                )));
             }
         } else {
             if !docker_service_names.contains(service_name_pattern) {
-                return Err(format!(
+                return Err(Error::config(format!(
                     "Configuration error: service {} does not exist",
                     service_name_pattern
-                )
-                .into());
+                )));
             }
 
             if services.contains(service_name_pattern) {
-                return Err(
-                    "Configuration error: service name cannot be used multiple times".into(),
-                );
+                return Err(Error::config(
+                    "Configuration error: service name cannot be used multiple times",
+                ));
             } else {
                 services.push(service_name_pattern.to_owned());
             }
@@ -303,16 +664,17 @@ pub async fn match_services(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::{FakeDocker, FakeResponse};
 
     #[test]
     fn test_list_env_pairs_to_update_no_changes() {
         let mut old_env_vars = HashMap::new();
-        old_env_vars.insert("VAR1".to_string(), "old_value1".to_string());
-        old_env_vars.insert("VAR2".to_string(), "old_value2".to_string());
+        old_env_vars.insert("VAR1".to_string(), "old_value1".into());
+        old_env_vars.insert("VAR2".to_string(), "old_value2".into());
 
         let mut new_env_vars = HashMap::new();
-        new_env_vars.insert("VAR1".to_string(), "old_value1".to_string());
-        new_env_vars.insert("VAR2".to_string(), "old_value2".to_string());
+        new_env_vars.insert("VAR1".to_string(), "old_value1".into());
+        new_env_vars.insert("VAR2".to_string(), "old_value2".into());
 
         let result = list_env_pairs_to_update(old_env_vars, new_env_vars).unwrap();
         assert!(result.is_empty()); // No changes, so the result should be an empty HashMap
@@ -321,32 +683,32 @@ mod tests {
     #[test]
     fn test_list_env_pairs_to_update_with_changes() {
         let mut old_env_vars = HashMap::new();
-        old_env_vars.insert("VAR1".to_string(), "old_value1".to_string());
-        old_env_vars.insert("VAR2".to_string(), "old_value2".to_string());
+        old_env_vars.insert("VAR1".to_string(), "old_value1".into());
+        old_env_vars.insert("VAR2".to_string(), "old_value2".into());
 
         let mut new_env_vars = HashMap::new();
-        new_env_vars.insert("VAR1".to_string(), "new_value1".to_string());
-        new_env_vars.insert("VAR2".to_string(), "old_value2".to_string());
+        new_env_vars.insert("VAR1".to_string(), "new_value1".into());
+        new_env_vars.insert("VAR2".to_string(), "old_value2".into());
 
         let result = list_env_pairs_to_update(old_env_vars, new_env_vars).unwrap();
         let mut expected_result = HashMap::new();
-        expected_result.insert("VAR1".to_string(), "new_value1".to_string());
+        expected_result.insert("VAR1".to_string(), "new_value1".into());
         assert_eq!(result, expected_result); // VAR1 has changed, so it should be in the result
     }
 
     #[test]
     fn test_list_env_pairs_to_update_missing_old_vars() {
         let mut old_env_vars = HashMap::new();
-        old_env_vars.insert("VAR1".to_string(), "old_value1".to_string());
+        old_env_vars.insert("VAR1".to_string(), "old_value1".into());
 
         let mut new_env_vars = HashMap::new();
-        new_env_vars.insert("VAR1".to_string(), "new_value1".to_string());
-        new_env_vars.insert("VAR2".to_string(), "new_value2".to_string());
+        new_env_vars.insert("VAR1".to_string(), "new_value1".into());
+        new_env_vars.insert("VAR2".to_string(), "new_value2".into());
 
         let result = list_env_pairs_to_update(old_env_vars, new_env_vars).unwrap();
         let mut expected_result = HashMap::new();
-        expected_result.insert("VAR1".to_string(), "new_value1".to_string());
-        expected_result.insert("VAR2".to_string(), "new_value2".to_string());
+        expected_result.insert("VAR1".to_string(), "new_value1".into());
+        expected_result.insert("VAR2".to_string(), "new_value2".into());
         // VAR1 has changed, VAR2 is a new variable, both should be in the result
         assert_eq!(result, expected_result);
     }
@@ -354,16 +716,16 @@ mod tests {
     #[test]
     fn test_list_env_pairs_to_update_new_vars_not_in_old_vars() {
         let mut old_env_vars = HashMap::new();
-        old_env_vars.insert("VAR1".to_string(), "old_value1".to_string());
+        old_env_vars.insert("VAR1".to_string(), "old_value1".into());
 
         let mut new_env_vars = HashMap::new();
-        new_env_vars.insert("VAR2".to_string(), "new_value2".to_string());
-        new_env_vars.insert("VAR3".to_string(), "new_value3".to_string());
+        new_env_vars.insert("VAR2".to_string(), "new_value2".into());
+        new_env_vars.insert("VAR3".to_string(), "new_value3".into());
 
         let result = list_env_pairs_to_update(old_env_vars, new_env_vars).unwrap();
         let mut expected_result = HashMap::new();
-        expected_result.insert("VAR2".to_string(), "new_value2".to_string());
-        expected_result.insert("VAR3".to_string(), "new_value3".to_string());
+        expected_result.insert("VAR2".to_string(), "new_value2".into());
+        expected_result.insert("VAR3".to_string(), "new_value3".into());
         // VAR2 and VAR3 are new variables, both should be in the result
         assert_eq!(result, expected_result);
     }
@@ -422,20 +784,34 @@ mod tests {
         assert!(is_match("pattern_end", "pattern_en*"));
     }
 
+    #[test]
+    fn test_matches_watcher() {
+        let watcher = Watcher {
+            docker_services: vec!["backend".to_owned(), "staging-*".to_owned()],
+            ..Default::default()
+        };
+
+        assert!(matches_watcher(&watcher, "backend"));
+        assert!(matches_watcher(&watcher, "staging-pr-42"));
+        assert!(!matches_watcher(&watcher, "backend-worker"));
+        assert!(!matches_watcher(&watcher, "production-web"));
+    }
+
     #[tokio::test]
     async fn test_match_services_exact_match() {
         let watcher = Watcher {
             name: "My watcher".to_owned(),
             docker_services: vec!["service1".to_owned(), "service2".to_owned()],
             doppler_token: "secret".to_owned(),
+            ..Default::default()
         };
 
         let docker_service_names = vec!["service1".to_owned(), "service2".to_owned()];
 
         let result = match_services(&watcher, docker_service_names).await;
         assert_eq!(
-            result,
-            Ok(vec!["service1".to_owned(), "service2".to_owned()])
+            result.unwrap(),
+            vec!["service1".to_owned(), "service2".to_owned()]
         );
     }
 
@@ -445,6 +821,7 @@ mod tests {
             name: "My watcher".to_owned(),
             docker_services: vec!["service*".to_owned()],
             doppler_token: "secret".to_owned(),
+            ..Default::default()
         };
 
         let docker_service_names = vec![
@@ -455,8 +832,8 @@ mod tests {
 
         let result = match_services(&watcher, docker_service_names).await;
         assert_eq!(
-            result,
-            Ok(vec!["service1".to_owned(), "service2".to_owned()])
+            result.unwrap(),
+            vec!["service1".to_owned(), "service2".to_owned()]
         );
     }
 
@@ -466,15 +843,19 @@ mod tests {
             name: "My watcher".to_owned(),
             docker_services: vec!["service1".to_owned()],
             doppler_token: "secret".to_owned(),
+            ..Default::default()
         };
 
         let docker_service_names = vec!["service2".to_owned(), "another_service".to_owned()];
 
-        let result = match_services(&watcher, docker_service_names).await;
+        let error = match_services(&watcher, docker_service_names)
+            .await
+            .unwrap_err();
         assert_eq!(
-            result,
-            Err("Configuration error: service service1 does not exist".into())
+            error.to_string(),
+            "Configuration error: service service1 does not exist"
         );
+        assert_eq!(error.exit_code(), 2);
     }
 
     #[tokio::test]
@@ -483,15 +864,19 @@ mod tests {
             name: "My watcher".to_owned(),
             docker_services: vec!["service*".to_owned()],
             doppler_token: "secret".to_owned(),
+            ..Default::default()
         };
 
         let docker_service_names = vec!["another_service".to_owned()];
 
-        let result = match_services(&watcher, docker_service_names).await;
+        let error = match_services(&watcher, docker_service_names)
+            .await
+            .unwrap_err();
         assert_eq!(
-            result,
-            Err("Configuration error: no services match pattern service*".into())
+            error.to_string(),
+            "Configuration error: no services match pattern service*"
         );
+        assert_eq!(error.exit_code(), 2);
     }
 
     #[tokio::test]
@@ -500,41 +885,565 @@ mod tests {
             name: "My watcher".to_owned(),
             docker_services: vec!["my*".to_owned()],
             doppler_token: "secret".to_owned(),
+            ..Default::default()
         };
 
         let docker_service_names = vec!["myservice1".to_owned(), "myservice2".to_owned()];
 
         let result = match_services(&watcher, docker_service_names).await;
         assert_eq!(
-            result,
-            Ok(vec!["myservice1".to_owned(), "myservice2".to_owned()])
+            result.unwrap(),
+            vec!["myservice1".to_owned(), "myservice2".to_owned()]
         );
     }
 
     #[test]
     fn test_list_env_vars_to_delete_no_changes() {
         let mut old_env_vars = HashMap::new();
-        old_env_vars.insert("VAR1".to_string(), "old_value1".to_string());
-        old_env_vars.insert("VAR2".to_string(), "old_value2".to_string());
+        old_env_vars.insert("VAR1".to_string(), "old_value1".into());
+        old_env_vars.insert("VAR2".to_string(), "old_value2".into());
 
         let mut new_env_vars = HashMap::new();
-        new_env_vars.insert("VAR1".to_string(), "old_value1".to_string());
-        new_env_vars.insert("VAR2".to_string(), "old_value2".to_string());
+        new_env_vars.insert("VAR1".to_string(), "old_value1".into());
+        new_env_vars.insert("VAR2".to_string(), "old_value2".into());
 
-        let result = list_env_vars_to_delete(old_env_vars, new_env_vars).unwrap();
+        let managed_keys = HashSet::from(["VAR1".to_string(), "VAR2".to_string()]);
+
+        let result = list_env_vars_to_delete(old_env_vars, new_env_vars, &managed_keys).unwrap();
         assert!(result.is_empty()); // No changes, so the result should be an empty Vec
     }
 
     #[test]
     fn test_list_env_vars_to_delete_with_changes() {
         let mut old_env_vars = HashMap::new();
-        old_env_vars.insert("VAR1".to_string(), "old_value1".to_string());
-        old_env_vars.insert("VAR2".to_string(), "old_value2".to_string());
+        old_env_vars.insert("VAR1".to_string(), "old_value1".into());
+        old_env_vars.insert("VAR2".to_string(), "old_value2".into());
 
         let mut new_env_vars = HashMap::new();
-        new_env_vars.insert("VAR1".to_string(), "old_value1".to_string());
+        new_env_vars.insert("VAR1".to_string(), "old_value1".into());
+
+        let managed_keys = HashSet::from(["VAR1".to_string(), "VAR2".to_string()]);
 
-        let result = list_env_vars_to_delete(old_env_vars, new_env_vars).unwrap();
+        let result = list_env_vars_to_delete(old_env_vars, new_env_vars, &managed_keys).unwrap();
         assert_eq!(result, vec!["VAR2".to_string()]); // VAR2 has been removed, so it should be in the result
     }
+
+    #[test]
+    fn test_apply_env_changes() {
+        let env = vec![
+            "KEEP=1".to_string(),
+            "REMOVE=2".to_string(),
+            "CHANGE=3".to_string(),
+        ];
+
+        let mut env_vars_to_update = HashMap::new();
+        env_vars_to_update.insert("CHANGE".to_string(), "33".into());
+        env_vars_to_update.insert("ADD".to_string(), "4".into());
+
+        let result = apply_env_changes(&env, &["REMOVE".to_string()], &env_vars_to_update);
+        assert_eq!(
+            result,
+            vec![
+                "KEEP=1".to_string(),
+                "ADD=4".to_string(),
+                "CHANGE=33".to_string()
+            ]
+        );
+    }
+
+    const SERVICE_JSON: &str = r#"{
+        "ID": "abc123",
+        "Version": {"Index": 42},
+        "Spec": {
+            "Name": "backend",
+            "Labels": {
+                "com.docker.stack.namespace": "app",
+                "doppler-swarm.managed-keys": "VAR1,VAR2"
+            },
+            "TaskTemplate": {
+                "ContainerSpec": {
+                    "Image": "backend:latest",
+                    "Env": ["VAR1=old_value1", "VAR2=old_value2"]
+                },
+                "ForceUpdate": 0
+            },
+            "Mode": {"Replicated": {"Replicas": 2}}
+        }
+    }"#;
+
+    #[tokio::test]
+    async fn test_list_services() {
+        let docker = FakeDocker::start(|request| {
+            assert_eq!(request.path, "/services");
+            FakeResponse::json(200, format!("[{SERVICE_JSON}]"))
+        });
+
+        let watcher = Watcher {
+            name: "My watcher".to_owned(),
+            docker_services: vec!["back*".to_owned()],
+            doppler_token: "secret".to_owned(),
+            ..Default::default()
+        };
+
+        let result = list_services(&docker.engine(), &watcher).await;
+        assert_eq!(result.unwrap(), vec!["backend".to_owned()]);
+    }
+
+    #[tokio::test]
+    async fn test_inspect_service() {
+        let docker = FakeDocker::start(|request| {
+            assert_eq!(request.path, "/services/backend");
+            FakeResponse::json(200, SERVICE_JSON)
+        });
+
+        let service = inspect_service(&docker.engine(), "backend").await.unwrap();
+        assert_eq!(service.id, "abc123");
+        assert_eq!(service.version.index, 42);
+        assert_eq!(service.spec.name, "backend");
+    }
+
+    #[test]
+    fn test_get_current_env_vars() {
+        let service: Service = serde_json::from_str(SERVICE_JSON).unwrap();
+
+        let result = get_current_env_vars(&service).unwrap();
+
+        let mut expected = HashMap::new();
+        expected.insert("VAR1".to_string(), "old_value1".into());
+        expected.insert("VAR2".to_string(), "old_value2".into());
+        assert_eq!(result, expected);
+    }
+
+    #[tokio::test]
+    async fn test_update_service_posts_full_spec() {
+        let docker = FakeDocker::start(|request| match request.method.as_str() {
+            "GET" => FakeResponse::json(200, SERVICE_JSON),
+            _ => FakeResponse::json(200, r#"{"Warnings": null}"#),
+        });
+
+        let mut old_env_vars = HashMap::new();
+        old_env_vars.insert("VAR1".to_string(), "old_value1".into());
+        old_env_vars.insert("VAR2".to_string(), "old_value2".into());
+
+        let mut new_env_vars = HashMap::new();
+        new_env_vars.insert("VAR1".to_string(), "new_value1".into());
+
+        let engine = docker.engine();
+        let service = inspect_service(&engine, "backend").await.unwrap();
+        let outcome = update_service(&engine, &service, old_env_vars, new_env_vars)
+            .await
+            .unwrap();
+        assert_eq!(outcome, UpdateOutcome::Updated);
+
+        let requests = docker.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].method, "POST");
+        assert_eq!(requests[1].path, "/services/abc123/update?version=42");
+
+        let spec: serde_json::Value = serde_json::from_str(&requests[1].body).unwrap();
+        assert_eq!(
+            spec["TaskTemplate"]["ContainerSpec"]["Env"],
+            serde_json::json!(["VAR1=new_value1"])
+        );
+        assert_eq!(
+            spec["TaskTemplate"]["ContainerSpec"]["Image"],
+            "backend:latest"
+        );
+        assert_eq!(spec["Mode"]["Replicated"]["Replicas"], 2);
+        assert_eq!(spec["Labels"]["com.docker.stack.namespace"], "app");
+    }
+
+    #[test]
+    fn test_describe_env_changes_hides_values() {
+        let mut env_vars_to_update = HashMap::new();
+        env_vars_to_update.insert("API_KEY".to_string(), "super-secret".into());
+
+        let description = describe_env_changes(&["OLD_KEY".to_string()], &env_vars_to_update);
+        assert_eq!(description, r#"set ["API_KEY"], remove ["OLD_KEY"]"#);
+        assert!(!description.contains("super-secret"));
+    }
+
+    #[tokio::test]
+    async fn test_update_service_keeps_values_out_of_request_line() {
+        let docker = FakeDocker::start(|request| match request.method.as_str() {
+            "GET" => FakeResponse::json(200, SERVICE_JSON),
+            _ => FakeResponse::json(200, "{}"),
+        });
+
+        let mut old_env_vars = HashMap::new();
+        old_env_vars.insert("VAR1".to_string(), "old_value1".into());
+        old_env_vars.insert("VAR2".to_string(), "old_value2".into());
+
+        let mut new_env_vars = HashMap::new();
+        new_env_vars.insert("VAR1".to_string(), "very-secret-value".into());
+        new_env_vars.insert("VAR2".to_string(), "old_value2".into());
+
+        let engine = docker.engine();
+        let service = inspect_service(&engine, "backend").await.unwrap();
+        let outcome = update_service(&engine, &service, old_env_vars, new_env_vars)
+            .await
+            .unwrap();
+        assert_eq!(outcome, UpdateOutcome::Updated);
+
+        // Secret values travel only in the request body, never in the
+        // request line that ends up in proxy or daemon access logs.
+        for request in docker.requests() {
+            assert!(!request.path.contains("very-secret-value"));
+            assert!(!request.path.contains("old_value"));
+        }
+        assert!(docker.requests()[1].body.contains("VAR1=very-secret-value"));
+    }
+
+    fn task(state: &str, env: &[&str]) -> Task {
+        serde_json::from_value(serde_json::json!({
+            "Spec": {"ContainerSpec": {"Env": env}},
+            "Status": {"State": state},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_rollout_progress() {
+        let mut service: Service = serde_json::from_str(SERVICE_JSON).unwrap();
+        let new = ["VAR1=old_value1", "VAR2=old_value2"];
+        let old = ["VAR1=stale"];
+
+        // Update not picked up yet: tasks still run the old spec.
+        assert_eq!(
+            rollout_progress(
+                &service,
+                &[task("running", &new), task("running", &old)],
+                None
+            ),
+            Rollout::InProgress("1 of 2 tasks running the new spec".to_owned())
+        );
+        assert_eq!(
+            rollout_progress(
+                &service,
+                &[task("running", &new), task("starting", &new)],
+                None
+            ),
+            Rollout::InProgress("1 of 2 tasks running the new spec".to_owned())
+        );
+
+        service.update_status = Some(UpdateStatus {
+            state: Some("updating".to_owned()),
+            ..Default::default()
+        });
+        assert_eq!(
+            rollout_progress(&service, &[task("running", &new)], None),
+            Rollout::InProgress("updating".to_owned())
+        );
+
+        service.update_status = Some(UpdateStatus {
+            state: Some("completed".to_owned()),
+            message: Some("update completed".to_owned()),
+            ..Default::default()
+        });
+        assert_eq!(
+            rollout_progress(
+                &service,
+                &[task("running", &new), task("running", &new)],
+                None
+            ),
+            Rollout::Converged
+        );
+
+        service.update_status = Some(UpdateStatus {
+            state: Some("paused".to_owned()),
+            message: Some("update paused due to failure or early termination of task x".to_owned()),
+            ..Default::default()
+        });
+        assert_eq!(
+            rollout_progress(&service, &[], None),
+            Rollout::Failed(
+                "paused: update paused due to failure or early termination of task x".to_owned()
+            )
+        );
+
+        // A rollback left over from an earlier update is not ours.
+        let stale = UpdateStatus {
+            state: Some("rollback_completed".to_owned()),
+            started_at: Some("2024-03-01T12:00:00.000000000Z".to_owned()),
+            message: Some("rollback completed".to_owned()),
+        };
+        service.update_status = Some(stale.clone());
+        assert_eq!(
+            rollout_progress(&service, &[task("running", &new)], Some(&stale)),
+            Rollout::Converged
+        );
+        assert_eq!(
+            rollout_progress(&service, &[task("running", &old)], Some(&stale)),
+            Rollout::InProgress("0 of 1 tasks running the new spec".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_rollout_reports_rollback() {
+        let docker = FakeDocker::start(|request| {
+            if request.path.starts_with("/tasks") {
+                return FakeResponse::json(200, "[]");
+            }
+
+            let mut service: serde_json::Value = serde_json::from_str(SERVICE_JSON).unwrap();
+            service["UpdateStatus"] = serde_json::json!({
+                "State": "rollback_completed",
+                "Message": "rollback completed",
+            });
+            FakeResponse::json(200, service.to_string())
+        });
+
+        let error = wait_for_rollout(
+            &docker.engine(),
+            &serde_json::from_str(SERVICE_JSON).unwrap(),
+            "backend",
+            Duration::from_secs(60),
+            &mut tokio::sync::watch::channel(false).1,
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(error, Error::DockerRollout { .. }));
+        assert_eq!(
+            error.to_string(),
+            "Rolling update of service backend rolled back: rollback completed"
+        );
+        assert_eq!(
+            docker.requests()[1].path,
+            "/tasks?filters=%7B%22service%22%3A%5B%22abc123%22%5D%2C%22desired-state%22%3A%5B%22running%22%5D%7D"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_rollout_times_out() {
+        let docker = FakeDocker::start(|request| {
+            if request.path.starts_with("/tasks") {
+                return FakeResponse::json(200, "[]");
+            }
+
+            let mut service: serde_json::Value = serde_json::from_str(SERVICE_JSON).unwrap();
+            service["UpdateStatus"] = serde_json::json!({"State": "updating"});
+            FakeResponse::json(200, service.to_string())
+        });
+
+        let error = wait_for_rollout(
+            &docker.engine(),
+            &serde_json::from_str(SERVICE_JSON).unwrap(),
+            "backend",
+            Duration::from_millis(50),
+            &mut tokio::sync::watch::channel(false).1,
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(error, Error::DockerRollout { .. }));
+        assert_eq!(
+            error.to_string(),
+            "Rolling update of service backend did not converge within 50ms: updating"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_rollout_ignores_stale_rollback() {
+        let stale = serde_json::json!({
+            "State": "rollback_completed",
+            "StartedAt": "2024-03-01T12:00:00.000000000Z",
+            "Message": "rollback completed",
+        });
+        let docker = FakeDocker::start({
+            let stale = stale.clone();
+            move |request| {
+                if request.path.starts_with("/tasks") {
+                    return FakeResponse::json(
+                        200,
+                        r#"[{"Spec": {"ContainerSpec": {"Env": ["VAR1=old_value1", "VAR2=old_value2"]}},
+                             "Status": {"State": "running"}}]"#,
+                    );
+                }
+
+                let mut service: serde_json::Value = serde_json::from_str(SERVICE_JSON).unwrap();
+                service["UpdateStatus"] = stale.clone();
+                FakeResponse::json(200, service.to_string())
+            }
+        });
+
+        // The service had been rolled back before we updated it.
+        let mut before: serde_json::Value = serde_json::from_str(SERVICE_JSON).unwrap();
+        before["UpdateStatus"] = stale;
+
+        let rollout = wait_for_rollout(
+            &docker.engine(),
+            &serde_json::from_value(before).unwrap(),
+            "backend",
+            Duration::from_secs(60),
+            &mut tokio::sync::watch::channel(false).1,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(rollout, Rollout::Converged);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_rollout_stops_on_shutdown() {
+        let docker = FakeDocker::start(|request| {
+            if request.path.starts_with("/tasks") {
+                return FakeResponse::json(200, "[]");
+            }
+
+            let mut service: serde_json::Value = serde_json::from_str(SERVICE_JSON).unwrap();
+            service["UpdateStatus"] = serde_json::json!({"State": "updating"});
+            FakeResponse::json(200, service.to_string())
+        });
+        let (stop, mut rx) = tokio::sync::watch::channel(false);
+
+        let engine = docker.engine();
+        let service: Service = serde_json::from_str(SERVICE_JSON).unwrap();
+        let wait = wait_for_rollout(
+            &engine,
+            &service,
+            "backend",
+            Duration::from_secs(3600),
+            &mut rx,
+        );
+        tokio::pin!(wait);
+
+        // Let it poll once, then shut down.
+        assert!(tokio::time::timeout(Duration::from_millis(100), &mut wait)
+            .await
+            .is_err());
+        stop.send(true).unwrap();
+
+        let rollout = tokio::time::timeout(Duration::from_secs(5), wait)
+            .await
+            .expect("Rollout wait did not stop")
+            .unwrap();
+        assert_eq!(rollout, Rollout::InProgress("updating".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn test_update_service_reports_version_conflict() {
+        let docker = FakeDocker::start(|_| {
+            FakeResponse::json(
+                500,
+                r#"{"message": "rpc error: code = Unknown desc = update out of sequence"}"#,
+            )
+        });
+
+        let service: Service = serde_json::from_str(SERVICE_JSON).unwrap();
+
+        let mut new_env_vars = HashMap::new();
+        new_env_vars.insert("VAR1".to_string(), "new_value1".into());
+
+        let outcome = update_service(
+            &docker.engine(),
+            &service,
+            get_current_env_vars(&service).unwrap(),
+            new_env_vars,
+        )
+        .await
+        .unwrap();
+        assert_eq!(outcome, UpdateOutcome::VersionConflict);
+    }
+
+    #[test]
+    fn test_list_env_vars_to_delete_keeps_foreign_keys() {
+        let mut old_env_vars = HashMap::new();
+        old_env_vars.insert("VAR1".to_string(), "old_value1".into());
+        old_env_vars.insert("VAR2".to_string(), "old_value2".into());
+        old_env_vars.insert("SERVICE_ROLE".to_string(), "worker".into());
+
+        let new_env_vars = HashMap::new();
+        let managed_keys = HashSet::from(["VAR1".to_string()]);
+
+        let result = list_env_vars_to_delete(old_env_vars, new_env_vars, &managed_keys).unwrap();
+        assert_eq!(result, vec!["VAR1".to_string()]); // VAR2 and SERVICE_ROLE were never managed by us
+    }
+
+    #[test]
+    fn test_managed_keys() {
+        let mut service: Service = serde_json::from_str(SERVICE_JSON).unwrap();
+        assert_eq!(
+            managed_keys(&service),
+            HashSet::from(["VAR1".to_string(), "VAR2".to_string()])
+        );
+
+        service.spec.labels = None;
+        assert!(managed_keys(&service).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_update_service_records_managed_keys() {
+        let docker = FakeDocker::start(|_| FakeResponse::json(200, "{}"));
+
+        let mut service: Service = serde_json::from_str(SERVICE_JSON).unwrap();
+        service.spec.task_template.container_spec.env = Some(vec![
+            "VAR1=old_value1".to_string(),
+            "SERVICE_ROLE=web".to_string(),
+        ]);
+
+        let mut new_env_vars = HashMap::new();
+        new_env_vars.insert("VAR1".to_string(), "old_value1".into());
+        new_env_vars.insert("VAR3".to_string(), "new_value3".into());
+
+        update_service(
+            &docker.engine(),
+            &service,
+            get_current_env_vars(&service).unwrap(),
+            new_env_vars,
+        )
+        .await
+        .unwrap();
+
+        let spec: serde_json::Value = serde_json::from_str(&docker.requests()[0].body).unwrap();
+        assert_eq!(
+            spec["TaskTemplate"]["ContainerSpec"]["Env"],
+            serde_json::json!(["VAR1=old_value1", "SERVICE_ROLE=web", "VAR3=new_value3"])
+        );
+        assert_eq!(spec["Labels"][MANAGED_KEYS_LABEL], "VAR1,VAR3");
+        assert_eq!(spec["Labels"]["com.docker.stack.namespace"], "app");
+    }
+
+    #[test]
+    fn test_plan_env_changes() {
+        let mut old_env_vars = HashMap::new();
+        old_env_vars.insert("VAR1".to_string(), "old_value1".into());
+        old_env_vars.insert("VAR2".to_string(), "old_value2".into());
+        old_env_vars.insert("VAR3".to_string(), "old_value3".into());
+        old_env_vars.insert("ROLE".to_string(), "web".into());
+
+        let mut new_env_vars = HashMap::new();
+        new_env_vars.insert("VAR1".to_string(), "old_value1".into());
+        new_env_vars.insert("VAR2".to_string(), "new_value2".into());
+        new_env_vars.insert("VAR4".to_string(), "new_value4".into());
+
+        let managed_keys =
+            HashSet::from(["VAR1".to_string(), "VAR2".to_string(), "VAR3".to_string()]);
+
+        let plan = plan_env_changes(&old_env_vars, &new_env_vars, &managed_keys).unwrap();
+        assert_eq!(
+            plan,
+            vec![
+                PlannedChange::Change {
+                    name: "VAR2".to_string(),
+                    old_value: "old_value2".into(),
+                    new_value: "new_value2".into(),
+                },
+                PlannedChange::Remove {
+                    name: "VAR3".to_string(),
+                    old_value: "old_value3".into(),
+                },
+                PlannedChange::Add {
+                    name: "VAR4".to_string(),
+                    value: "new_value4".into(),
+                },
+            ]
+        );
+
+        let described: Vec<String> = plan.iter().map(|change| change.to_string()).collect();
+        assert!(described[0].starts_with("change VAR2: <redacted sha256:"));
+        assert_eq!(described[1], "remove VAR3");
+        assert!(described[2].starts_with("add VAR4=<redacted sha256:"));
+        assert!(described.iter().all(|line| !line.contains("value")));
+    }
 }
@@ -0,0 +1,264 @@
+use std::path::PathBuf;
+
+use bytes::Bytes;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+pub const DEFAULT_DOCKER_SOCKET: &str = "/var/run/docker.sock";
+
+/// Docker Engine API client talking HTTP/1.1 over the daemon's Unix socket.
+#[derive(Debug, Clone)]
+pub struct Engine {
+    socket: PathBuf,
+}
+
+#[derive(Debug)]
+pub enum EngineError {
+    /// The request could not be sent or its response could not be read.
+    Request(String),
+    /// The daemon answered with a non-2xx status.
+    Status {
+        request: String,
+        status: StatusCode,
+        message: String,
+    },
+}
+
+impl EngineError {
+    /// Swarm rejects updates whose `version` is not the current spec index.
+    pub fn is_version_conflict(&self) -> bool {
+        match self {
+            Self::Status {
+                status, message, ..
+            } => *status == StatusCode::CONFLICT || message.contains("update out of sequence"),
+            Self::Request(_) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for EngineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Request(msg) => f.write_str(msg),
+            Self::Status {
+                request,
+                status,
+                message,
+            } => write!(f, "Docker API {request} returned {status}: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+type Result<T> = std::result::Result<T, EngineError>;
+
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    message: String,
+}
+
+impl Engine {
+    pub fn new(socket: impl Into<PathBuf>) -> Self {
+        Self {
+            socket: socket.into(),
+        }
+    }
+
+    pub async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let body = self.request(Method::GET, path, Body::empty()).await?;
+
+        decode(path, &body)
+    }
+
+    pub async fn post<B: Serialize, T: DeserializeOwned>(
+        &self,
+        path: &str,
+        payload: &B,
+    ) -> Result<T> {
+        let payload = serde_json::to_vec(payload).map_err(|e| {
+            EngineError::Request(format!("Failed to encode request body for {path}: {e}"))
+        })?;
+
+        let body = self
+            .request(Method::POST, path, Body::from(payload))
+            .await?;
+
+        decode(path, &body)
+    }
+
+    /// Opens a long-lived GET such as `/events` and returns the body to be
+    /// read as the daemon writes it.
+    pub async fn stream(&self, path: &str) -> Result<Body> {
+        let response = self.send(Method::GET, path, Body::empty()).await?;
+
+        Ok(response.into_body())
+    }
+
+    async fn request(&self, method: Method, path: &str, body: Body) -> Result<Bytes> {
+        let response = self.send(method.clone(), path, body).await?;
+
+        hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(|e| {
+                EngineError::Request(format!(
+                    "Failed to read response body of {method} {path}: {e}"
+                ))
+            })
+    }
+
+    /// Sends a request and returns the response once its status is known to
+    /// be a success.
+    async fn send(&self, method: Method, path: &str, body: Body) -> Result<Response<Body>> {
+        let stream = tokio::net::UnixStream::connect(&self.socket)
+            .await
+            .map_err(|e| {
+                EngineError::Request(format!(
+                    "Failed to connect to docker socket {}: {e}",
+                    self.socket.display()
+                ))
+            })?;
+
+        let (mut sender, connection) =
+            hyper::client::conn::handshake(stream).await.map_err(|e| {
+                EngineError::Request(format!("Failed to handshake with docker socket: {e}"))
+            })?;
+
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                log::debug!("Docker socket connection closed with error: {e}");
+            }
+        });
+
+        let request = Request::builder()
+            .method(method.clone())
+            .uri(path)
+            .header(hyper::header::HOST, "docker")
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .map_err(|e| {
+                EngineError::Request(format!("Failed to build request {method} {path}: {e}"))
+            })?;
+
+        let response = sender
+            .send_request(request)
+            .await
+            .map_err(|e| EngineError::Request(format!("Request {method} {path} failed: {e}")))?;
+
+        let status = response.status();
+
+        if !status.is_success() {
+            let body = hyper::body::to_bytes(response.into_body())
+                .await
+                .unwrap_or_default();
+
+            return Err(EngineError::Status {
+                request: format!("{method} {path}"),
+                status,
+                message: error_message(status, &body),
+            });
+        }
+
+        Ok(response)
+    }
+}
+
+// The body is left out of the error on purpose: service specs carry env vars.
+fn decode<T: DeserializeOwned>(path: &str, body: &[u8]) -> Result<T> {
+    serde_json::from_slice(body).map_err(|e| {
+        EngineError::Request(format!(
+            "Failed to parse docker API response for {path}: {e}"
+        ))
+    })
+}
+
+fn error_message(status: StatusCode, body: &[u8]) -> String {
+    match serde_json::from_slice::<ErrorResponse>(body) {
+        Ok(error) => error.message,
+        Err(_) if body.is_empty() => status
+            .canonical_reason()
+            .unwrap_or("unknown error")
+            .to_owned(),
+        Err(_) => String::from_utf8_lossy(body).into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakeDocker, FakeResponse};
+
+    #[derive(Debug, Deserialize)]
+    struct Pong {
+        ok: bool,
+    }
+
+    #[tokio::test]
+    async fn test_get_decodes_json() {
+        let docker = FakeDocker::start(|request| {
+            assert_eq!(request.method, "GET");
+            assert_eq!(request.path, "/ping");
+            FakeResponse::json(200, r#"{"ok": true}"#)
+        });
+
+        let pong: Pong = docker.engine().get("/ping").await.unwrap();
+        assert!(pong.ok);
+    }
+
+    #[tokio::test]
+    async fn test_post_sends_json_body() {
+        let docker = FakeDocker::start(|_| FakeResponse::json(200, r#"{"ok": true}"#));
+
+        let _: Pong = docker
+            .engine()
+            .post("/things?version=3", &serde_json::json!({"Name": "thing"}))
+            .await
+            .unwrap();
+
+        let requests = docker.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].path, "/things?version=3");
+        assert_eq!(requests[0].body, r#"{"Name":"thing"}"#);
+    }
+
+    #[tokio::test]
+    async fn test_error_message_is_surfaced() {
+        let docker = FakeDocker::start(|_| {
+            FakeResponse::json(404, r#"{"message": "service missing not found"}"#)
+        });
+
+        let result: Result<Pong> = docker.engine().get("/services/missing").await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Docker API GET /services/missing returned 404 Not Found: service missing not found"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_missing_socket() {
+        let engine = Engine::new("/nonexistent/docker.sock");
+
+        let result: Result<Pong> = engine.get("/ping").await;
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .starts_with("Failed to connect to docker socket /nonexistent/docker.sock"));
+    }
+
+    #[tokio::test]
+    async fn test_version_conflict() {
+        let docker = FakeDocker::start(|_| {
+            FakeResponse::json(
+                500,
+                r#"{"message": "rpc error: code = Unknown desc = update out of sequence"}"#,
+            )
+        });
+
+        let result: Result<Pong> = docker
+            .engine()
+            .post("/services/x/update?version=1", &())
+            .await;
+        assert!(result.unwrap_err().is_version_conflict());
+    }
+}
@@ -1,28 +1,307 @@
-#[derive(Clone, PartialEq, Eq)]
-pub struct Error(String);
+use std::time::Duration;
 
+type Source = Box<dyn std::error::Error + Send + Sync>;
+
+/// Everything that can go wrong, grouped by what should happen next: whether
+/// a worker retries, and which exit code the process ends with.
+#[derive(Debug)]
+pub enum Error {
+    /// Bad command line, unreadable config file or invalid settings.
+    Config {
+        message: String,
+        source: Option<Source>,
+    },
+    /// Doppler rejected the token, or the plan does not support watching.
+    DopplerAuth {
+        message: String,
+        source: Option<Source>,
+    },
+    /// A Doppler request failed or returned an unexpected status.
+    DopplerHttp {
+        message: String,
+        source: Option<Source>,
+    },
+    /// Doppler asked us to slow down.
+    DopplerRateLimited {
+        message: String,
+        retry_after: Duration,
+    },
+    /// The watch stream broke, timed out or sent something unparseable.
+    DopplerStream {
+        message: String,
+        source: Option<Source>,
+    },
+    /// Listing or inspecting services failed, or a service doesn't match the
+    /// config.
+    DockerInspect {
+        message: String,
+        source: Option<Source>,
+    },
+    /// Updating a service failed.
+    DockerUpdate {
+        message: String,
+        source: Option<Source>,
+    },
+    /// A rolling update paused, rolled back or didn't converge in time.
+    /// Applying the same env again would most likely fail the same way.
+    DockerRollout {
+        message: String,
+        source: Option<Source>,
+    },
+    /// A webhook notification could not be delivered.
+    Webhook {
+        message: String,
+        source: Option<Source>,
+    },
+    /// A bug or a crashed task.
+    Internal {
+        message: String,
+        source: Option<Source>,
+    },
+}
+
+impl Error {
+    pub fn config(message: impl Into<String>) -> Self {
+        Self::Config {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    /// A Doppler error status: an auth error if retrying can't help,
+    /// otherwise an HTTP error.
+    pub fn doppler(message: impl Into<String>, error: crate::secrets::DopplerError) -> Self {
+        let message = message.into();
+        let permanent = error.is_permanent();
+        let source = Some(Box::new(error) as Source);
+
+        if permanent {
+            Self::DopplerAuth { message, source }
+        } else {
+            Self::DopplerHttp { message, source }
+        }
+    }
+
+    pub fn doppler_http(message: impl Into<String>, source: impl Into<Source>) -> Self {
+        Self::DopplerHttp {
+            message: message.into(),
+            source: Some(source.into()),
+        }
+    }
+
+    pub fn doppler_stream(message: impl Into<String>) -> Self {
+        Self::DopplerStream {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    pub fn docker_inspect(message: impl Into<String>) -> Self {
+        Self::DockerInspect {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    pub fn docker_update(message: impl Into<String>) -> Self {
+        Self::DockerUpdate {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    pub fn docker_rollout(message: impl Into<String>) -> Self {
+        Self::DockerRollout {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    pub fn webhook(message: impl Into<String>) -> Self {
+        Self::Webhook {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::Internal {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    /// Sets the underlying cause, keeping the kind and message.
+    pub fn with_source(mut self, cause: impl Into<Source>) -> Self {
+        match &mut self {
+            Self::Config { source, .. }
+            | Self::DopplerAuth { source, .. }
+            | Self::DopplerHttp { source, .. }
+            | Self::DopplerStream { source, .. }
+            | Self::DockerInspect { source, .. }
+            | Self::DockerUpdate { source, .. }
+            | Self::DockerRollout { source, .. }
+            | Self::Webhook { source, .. }
+            | Self::Internal { source, .. } => *source = Some(cause.into()),
+            Self::DopplerRateLimited { .. } => {}
+        }
+        self
+    }
+
+    /// Prefixes the message with what we were doing, keeping the kind.
+    pub fn context(mut self, context: impl std::fmt::Display) -> Self {
+        let message = self.message_mut();
+        *message = format!("{context}: {message}");
+        self
+    }
+
+    /// Whether trying again later can succeed without a config change.
+    pub fn is_retryable(&self) -> bool {
+        !matches!(
+            self,
+            Self::Config { .. }
+                | Self::DopplerAuth { .. }
+                | Self::DockerRollout { .. }
+                | Self::Internal { .. }
+        )
+    }
+
+    /// How long Doppler asked us to wait before the next request.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::DopplerRateLimited { retry_after, .. } => Some(*retry_after),
+            _ => None,
+        }
+    }
+
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Internal { .. } => 1,
+            Self::Config { .. } => 2,
+            Self::DopplerAuth { .. } => 3,
+            Self::DopplerHttp { .. }
+            | Self::DopplerRateLimited { .. }
+            | Self::DopplerStream { .. } => 4,
+            Self::DockerInspect { .. } | Self::DockerUpdate { .. } | Self::DockerRollout { .. } => {
+                5
+            }
+            Self::Webhook { .. } => 6,
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            Self::Config { message, .. }
+            | Self::DopplerAuth { message, .. }
+            | Self::DopplerHttp { message, .. }
+            | Self::DopplerRateLimited { message, .. }
+            | Self::DopplerStream { message, .. }
+            | Self::DockerInspect { message, .. }
+            | Self::DockerUpdate { message, .. }
+            | Self::DockerRollout { message, .. }
+            | Self::Webhook { message, .. }
+            | Self::Internal { message, .. } => message,
+        }
+    }
+
+    fn message_mut(&mut self) -> &mut String {
+        match self {
+            Self::Config { message, .. }
+            | Self::DopplerAuth { message, .. }
+            | Self::DopplerHttp { message, .. }
+            | Self::DopplerRateLimited { message, .. }
+            | Self::DopplerStream { message, .. }
+            | Self::DockerInspect { message, .. }
+            | Self::DockerUpdate { message, .. }
+            | Self::DockerRollout { message, .. }
+            | Self::Webhook { message, .. }
+            | Self::Internal { message, .. } => message,
+        }
+    }
+}
+
+/// Shows the message followed by the chain of causes, so a single log line
+/// says both what we were doing and why it failed.
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&self.0)
+        f.write_str(self.message())?;
+
+        let mut source = std::error::Error::source(self);
+        while let Some(cause) = source {
+            write!(f, ": {cause}")?;
+            source = cause.source();
+        }
+
+        Ok(())
     }
 }
 
-impl std::fmt::Debug for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&self.0)
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Config { source, .. }
+            | Self::DopplerAuth { source, .. }
+            | Self::DopplerHttp { source, .. }
+            | Self::DopplerStream { source, .. }
+            | Self::DockerInspect { source, .. }
+            | Self::DockerUpdate { source, .. }
+            | Self::DockerRollout { source, .. }
+            | Self::Webhook { source, .. }
+            | Self::Internal { source, .. } => source
+                .as_deref()
+                .map(|source| source as &(dyn std::error::Error + 'static)),
+            Self::DopplerRateLimited { .. } => None,
+        }
     }
 }
 
-impl std::error::Error for Error {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_includes_context_and_sources() {
+        let io = std::io::Error::new(std::io::ErrorKind::NotFound, "no such file");
+        let error = Error::config("Failed to read config file config.json")
+            .with_source(io)
+            .context("Startup");
 
-impl From<String> for Error {
-    fn from(msg: String) -> Self {
-        Self(msg)
+        assert_eq!(
+            error.to_string(),
+            "Startup: Failed to read config file config.json: no such file"
+        );
+        assert_eq!(
+            std::error::Error::source(&error).unwrap().to_string(),
+            "no such file"
+        );
+        assert!(matches!(error, Error::Config { .. }));
     }
-}
 
-impl From<&str> for Error {
-    fn from(msg: &str) -> Self {
-        Self(msg.to_owned())
+    #[test]
+    fn test_retryability_and_exit_codes() {
+        let config = Error::config("bad");
+        assert!(!config.is_retryable());
+        assert_eq!(config.exit_code(), 2);
+
+        let stream = Error::doppler_stream("Watch stream ended unexpectedly");
+        assert!(stream.is_retryable());
+        assert_eq!(stream.exit_code(), 4);
+
+        let rate_limited = Error::DopplerRateLimited {
+            message: "slow down".to_owned(),
+            retry_after: Duration::from_secs(7),
+        };
+        assert!(rate_limited.is_retryable());
+        assert_eq!(rate_limited.retry_after(), Some(Duration::from_secs(7)));
+
+        let update = Error::docker_update("version conflict");
+        assert!(update.is_retryable());
+        assert_eq!(update.exit_code(), 5);
+
+        let rollout = Error::docker_rollout("Rolling update of service backend was rolled back");
+        assert!(!rollout.is_retryable());
+        assert_eq!(rollout.exit_code(), 5);
+
+        assert_eq!(Error::webhook("Webhook returned 500").exit_code(), 6);
     }
 }
@@ -0,0 +1,225 @@
+use std::{collections::HashMap, time::Duration};
+
+use hyper::body::HttpBody;
+use serde::Deserialize;
+use tokio::sync::{broadcast, watch};
+
+use crate::{backoff::Backoff, engine::Engine, error::Error};
+
+/// URL-encoded `{"type":["service"]}`.
+const SERVICE_EVENTS_FILTER: &str = "%7B%22type%22%3A%5B%22service%22%5D%7D";
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// A Docker service event, e.g. a service being created by CI or updated by
+/// `docker stack deploy`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ServiceEvent {
+    /// `create`, `update` or `remove`.
+    #[serde(rename = "Action")]
+    pub action: String,
+    #[serde(rename = "Actor")]
+    pub actor: Actor,
+    /// Unix timestamp in seconds.
+    #[serde(default)]
+    pub time: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Actor {
+    #[serde(rename = "ID")]
+    pub id: String,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+}
+
+impl ServiceEvent {
+    pub fn service_name(&self) -> Option<&str> {
+        self.actor.attributes.get("name").map(|name| name.as_str())
+    }
+}
+
+/// Splits the `/events` body into events. The daemon writes one JSON
+/// document per line, but a line can arrive split across chunks.
+#[derive(Debug, Default)]
+pub struct EventDecoder {
+    buffer: Vec<u8>,
+}
+
+impl EventDecoder {
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<ServiceEvent> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut events = vec![];
+
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = line.trim_ascii();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            match serde_json::from_slice(line) {
+                Ok(event) => events.push(event),
+                Err(e) => log::warn!("Ignoring unparseable docker event: {e}"),
+            }
+        }
+
+        events
+    }
+}
+
+/// Follows Docker service events and broadcasts them to the workers until
+/// `stop` flips to true, reconnecting with backoff when the stream drops.
+pub async fn watch_service_events(
+    engine: Engine,
+    events: broadcast::Sender<ServiceEvent>,
+    mut stop: watch::Receiver<bool>,
+) {
+    let mut backoff = Backoff::new(RECONNECT_DELAY, MAX_RECONNECT_DELAY, u32::MAX);
+    let mut since = None;
+
+    while !*stop.borrow() {
+        let result = tokio::select! {
+            changed = stop.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                continue;
+            }
+            result = follow_service_events(&engine, &events, &mut since, &mut backoff) => result,
+        };
+
+        let Err(e) = result else {
+            continue;
+        };
+
+        let delay = backoff.next_delay();
+        log::warn!(
+            "{e}. Reconnecting to docker events in {:.1}s",
+            delay.as_secs_f64()
+        );
+
+        tokio::select! {
+            changed = stop.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+            _ = tokio::time::sleep(delay) => {}
+        }
+    }
+}
+
+/// Streams events until the connection drops. `since` resumes the stream
+/// after a reconnect so events in between are not lost.
+async fn follow_service_events(
+    engine: &Engine,
+    events: &broadcast::Sender<ServiceEvent>,
+    since: &mut Option<u64>,
+    backoff: &mut Backoff,
+) -> crate::result::Result<()> {
+    let mut path = format!("/events?filters={SERVICE_EVENTS_FILTER}");
+    if let Some(since) = since {
+        path.push_str(&format!("&since={since}"));
+    }
+
+    let mut body = engine.stream(&path).await.map_err(|e| {
+        Error::docker_inspect("Failed to subscribe to docker events").with_source(e)
+    })?;
+
+    log::info!("Watching docker service events");
+    backoff.reset();
+
+    let mut decoder = EventDecoder::default();
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk
+            .map_err(|e| Error::docker_inspect("Failed to read docker events").with_source(e))?;
+
+        for event in decoder.push(&chunk) {
+            log::debug!(
+                "Docker service event: {} {}",
+                event.action,
+                event.service_name().unwrap_or(&event.actor.id)
+            );
+
+            *since = Some(event.time);
+            // Nobody listening is fine, e.g. while workers start up.
+            let _ = events.send(event);
+        }
+    }
+
+    Err(Error::docker_inspect("Docker events stream ended"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{wait_until, FakeDocker};
+
+    const CREATE_EVENT: &str = r#"{"Type":"service","Action":"create","Actor":{"ID":"id-web","Attributes":{"name":"staging-web"}},"scope":"swarm","time":1700000000,"timeNano":1700000000000000000}"#;
+
+    #[test]
+    fn test_decode_events_split_across_chunks() {
+        let mut decoder = EventDecoder::default();
+        let (head, tail) = CREATE_EVENT.split_at(40);
+
+        assert!(decoder.push(head.as_bytes()).is_empty());
+        let events = decoder.push(format!("{tail}\n\n{CREATE_EVENT}\n").as_bytes());
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].action, "create");
+        assert_eq!(events[0].service_name(), Some("staging-web"));
+        assert_eq!(events[0].time, 1700000000);
+    }
+
+    #[test]
+    fn test_decode_skips_invalid_lines() {
+        let mut decoder = EventDecoder::default();
+
+        let events = decoder.push(format!("not json\n{CREATE_EVENT}\n").as_bytes());
+
+        assert_eq!(events.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_watch_broadcasts_and_resumes_after_reconnect() {
+        let docker = FakeDocker::swarm(&[]);
+        let (events, mut rx) = broadcast::channel(16);
+        let (tx, stop) = watch::channel(false);
+
+        let handle = tokio::spawn(watch_service_events(docker.engine(), events, stop));
+
+        wait_until("events stream", || docker.live_event_streams() == 1).await;
+        docker.create_service("staging-web", &[]);
+
+        let event = rx.recv().await.unwrap();
+        assert_eq!(event.service_name(), Some("staging-web"));
+
+        docker.close_event_streams();
+        wait_until("reconnect", || docker.live_event_streams() == 1).await;
+
+        let paths: Vec<String> = docker
+            .requests()
+            .into_iter()
+            .map(|request| request.path)
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                format!("/events?filters={SERVICE_EVENTS_FILTER}"),
+                format!("/events?filters={SERVICE_EVENTS_FILTER}&since=1700000000"),
+            ]
+        );
+
+        tx.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .expect("Events watcher did not stop")
+            .unwrap();
+    }
+}
@@ -0,0 +1,217 @@
+use std::{future::Future, io::Write};
+
+use log::kv::{Key, Value, VisitSource};
+use serde_json::json;
+
+tokio::task_local! {
+    /// Correlation id of the sync running in the current task.
+    static SYNC_ID: String;
+}
+
+/// Sets up logging from `LOG_LEVEL` and `LOG_FORMAT`. `LOG_FORMAT=json`
+/// writes one JSON object per line with `watcher`, `service`, `event`,
+/// `sync_id` and `outcome` as separate fields. The default text format puts
+/// the watcher and service in brackets in front of the message.
+pub fn init() {
+    let env = env_logger::Env::default().filter_or("LOG_LEVEL", "info");
+    let json = std::env::var("LOG_FORMAT").is_ok_and(|format| format.eq_ignore_ascii_case("json"));
+
+    env_logger::Builder::from_env(env)
+        .format(move |buf, record| {
+            let timestamp = buf.timestamp().to_string();
+            let line = if json {
+                json_line(record, &timestamp, sync_id().as_deref())
+            } else {
+                text_line(record, &timestamp)
+            };
+            writeln!(buf, "{line}")
+        })
+        .init();
+}
+
+/// Runs a sync with a fresh correlation id, attached to every line it logs,
+/// from the Doppler download to the last service update.
+pub async fn with_sync_id<F: Future>(sync: F) -> F::Output {
+    in_sync(new_sync_id(), sync).await
+}
+
+/// Runs a sync under an id handed over from another task, e.g. the watch
+/// stream that received the change.
+pub async fn in_sync<F: Future>(id: String, sync: F) -> F::Output {
+    SYNC_ID.scope(id, sync).await
+}
+
+/// Logs whatever `f` logs under `id`, for a sync that hasn't started yet.
+pub fn log_in_sync<R>(id: &str, f: impl FnOnce() -> R) -> R {
+    SYNC_ID.sync_scope(id.to_owned(), f)
+}
+
+pub fn new_sync_id() -> String {
+    format!("{:016x}", fastrand::u64(..))
+}
+
+/// The correlation id of the sync running in the current task, if any.
+pub fn sync_id() -> Option<String> {
+    SYNC_ID.try_with(|id| id.clone()).ok()
+}
+
+#[derive(Default)]
+struct Fields(Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+impl Fields {
+    fn of(record: &log::Record) -> Self {
+        let mut fields = Self::default();
+        let _ = record.key_values().visit(&mut fields);
+        fields
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn json_line(record: &log::Record, timestamp: &str, sync_id: Option<&str>) -> String {
+    let mut line = json!({});
+
+    for (key, value) in Fields::of(record).0 {
+        line[key] = value.into();
+    }
+
+    // Set last, so fields can't overwrite them.
+    line["timestamp"] = timestamp.into();
+    line["level"] = record.level().as_str().into();
+    line["target"] = record.target().into();
+    line["message"] = record.args().to_string().into();
+    if let Some(sync_id) = sync_id {
+        line["sync_id"] = sync_id.into();
+    }
+
+    line.to_string()
+}
+
+fn text_line(record: &log::Record, timestamp: &str) -> String {
+    let fields = Fields::of(record);
+    let mut prefix = String::new();
+
+    for key in ["watcher", "service"] {
+        if let Some(value) = fields.get(key) {
+            prefix.push_str(&format!("[{value}] "));
+        }
+    }
+
+    format!(
+        "[{} {:<5} {}] {}{}",
+        timestamp,
+        record.level(),
+        record.target(),
+        prefix,
+        record.args()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_line_has_separate_fields() {
+        let fields = [("watcher", "production app"), ("service", "backend")];
+        let record = log::Record::builder()
+            .args(format_args!("Service updated"))
+            .level(log::Level::Info)
+            .target("doppler_swarm::reconciler")
+            .key_values(&fields)
+            .build();
+
+        let line: serde_json::Value = serde_json::from_str(&json_line(
+            &record,
+            "2024-03-01T12:00:00Z",
+            Some("0123456789abcdef"),
+        ))
+        .unwrap();
+
+        assert_eq!(
+            line,
+            json!({
+                "timestamp": "2024-03-01T12:00:00Z",
+                "level": "INFO",
+                "target": "doppler_swarm::reconciler",
+                "message": "Service updated",
+                "watcher": "production app",
+                "service": "backend",
+                "sync_id": "0123456789abcdef",
+            })
+        );
+    }
+
+    #[test]
+    fn test_json_line_fields_do_not_overwrite_reserved_keys() {
+        let fields = [("message", "spoofed"), ("level", "ERROR"), ("sync_id", "x")];
+        let record = log::Record::builder()
+            .args(format_args!("Service updated"))
+            .level(log::Level::Info)
+            .target("doppler_swarm::reconciler")
+            .key_values(&fields)
+            .build();
+
+        let line: serde_json::Value = serde_json::from_str(&json_line(
+            &record,
+            "2024-03-01T12:00:00Z",
+            Some("0123456789abcdef"),
+        ))
+        .unwrap();
+
+        assert_eq!(line["message"], "Service updated");
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["sync_id"], "0123456789abcdef");
+    }
+
+    #[test]
+    fn test_text_line_prefixes_watcher_and_service() {
+        let fields = [("service", "backend"), ("watcher", "production app")];
+        let record = log::Record::builder()
+            .args(format_args!("Service updated"))
+            .level(log::Level::Warn)
+            .target("doppler_swarm::reconciler")
+            .key_values(&fields)
+            .build();
+
+        assert_eq!(
+            text_line(&record, "2024-03-01T12:00:00Z"),
+            "[2024-03-01T12:00:00Z WARN  doppler_swarm::reconciler] [production app] [backend] Service updated"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_id_is_scoped_to_the_sync() {
+        assert_eq!(sync_id(), None);
+
+        let (first, again) = with_sync_id(async { (sync_id(), sync_id()) }).await;
+        assert_eq!(first, again);
+        assert_eq!(first.as_deref().map(str::len), Some(16));
+
+        let next = with_sync_id(async { sync_id() }).await;
+        assert!(next.is_some());
+        assert_ne!(next, first);
+
+        let handed_over = new_sync_id();
+        assert_eq!(
+            log_in_sync(&handed_over, sync_id).as_ref(),
+            Some(&handed_over)
+        );
+        assert_eq!(
+            in_sync(handed_over.clone(), async { sync_id() }).await,
+            Some(handed_over)
+        );
+    }
+}
@@ -1,92 +1,369 @@
-use crate::worker::Worker;
+use crate::{reconciler::Reconciler, worker::Worker};
 use tokio::task::JoinError;
 
+mod audit;
+mod backoff;
+mod cli;
 mod config;
 mod docker;
+mod engine;
 mod error;
+mod events;
+mod logging;
+mod metrics;
+mod notify;
+mod reconciler;
 mod result;
 mod secrets;
+mod server;
+mod status;
+#[cfg(test)]
+mod testing;
 mod watch;
 mod worker;
 
+/// Docker service events buffered per watcher before it has to reconcile
+/// everything instead.
+const SERVICE_EVENTS_CAPACITY: usize = 256;
+
+/// Sync requests a watch stream can queue for its reconciler. More would
+/// only coalesce into the syncs already queued.
+const SYNC_REQUESTS_CAPACITY: usize = 16;
+
 #[tokio::main]
-async fn main() -> crate::result::Result<()> {
-    let env = env_logger::Env::default().filter_or("LOG_LEVEL", "info");
-    env_logger::init_from_env(env);
+async fn main() -> std::process::ExitCode {
+    logging::init();
+
+    match start().await {
+        Ok(()) => {
+            log::info!("Done.");
+            std::process::ExitCode::SUCCESS
+        }
+        Err(e) => {
+            log::error!("Exiting with code {}: {e}", e.exit_code());
+            std::process::ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+async fn start() -> crate::result::Result<()> {
+    let args = cli::parse_args(std::env::args().skip(1))?;
+    let config = config::read_config(&args)?;
 
-    let config = config::read_config()?;
+    match &args.command {
+        cli::Command::Run => {}
+        cli::Command::Audit(query) => return audit::print(&config, query),
+        cli::Command::NotifyTest { url } => {
+            return notify::send_test(&config, url.as_deref()).await
+        }
+    }
 
     let (tx, rx) = tokio::sync::watch::channel(false);
 
+    tokio::spawn(async move {
+        let mut sigint = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt())
+            .expect("Failed to create SIGINT signal handler");
+        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to create SIGTERM signal handler");
+
+        tokio::select! {
+            _ = sigint.recv() => {
+                log::info!("Received SIGINT, shutting down...");
+                tx.send(true).expect("Failed to send shutdown signal");
+            }
+            _ = sigterm.recv() => {
+                log::info!("Received SIGTERM, shutting down...");
+                tx.send(true).expect("Failed to send shutdown signal");
+            }
+        }
+    });
+
+    run(config, rx).await
+}
+
+/// Syncs every watcher once, then watches Doppler until `stop` flips to true.
+async fn run(
+    config: config::Config,
+    stop: tokio::sync::watch::Receiver<bool>,
+) -> crate::result::Result<()> {
+    let engine = engine::Engine::new(&config.docker_socket);
+
+    let status = match &config.status_server {
+        Some(status_server) => status::Status::new(status_server.ready_stream_max_age()),
+        None => status::Status::default(),
+    };
+    for watcher in &config.watchers {
+        status.register(&watcher.name);
+    }
+    let metrics = metrics::Metrics::new();
+    let auditor = config
+        .audit_log
+        .as_ref()
+        .map(audit::Auditor::new)
+        .transpose()?;
+
+    // Up before the startup sync, so health checks pass while it runs.
+    let server_handle = match &config.status_server {
+        Some(status_server) => {
+            let listener = tokio::net::TcpListener::bind(&status_server.listen)
+                .await
+                .map_err(|e| {
+                    error::Error::config(format!("Failed to listen on {}", status_server.listen))
+                        .with_source(e)
+                })?;
+
+            Some(tokio::spawn(server::serve(
+                listener,
+                status.clone(),
+                metrics.clone(),
+                stop.clone(),
+            )))
+        }
+        None => None,
+    };
+
+    // Subscribe before the startup sync so services created meanwhile are not missed.
+    let (service_events, _) = tokio::sync::broadcast::channel(SERVICE_EVENTS_CAPACITY);
+    let events_handle = tokio::spawn(events::watch_service_events(
+        engine.clone(),
+        service_events.clone(),
+        stop.clone(),
+    ));
+
     log::info!("Starting {} watchers...", config.watchers.len());
 
     let mut handles = Vec::with_capacity(config.watchers.len());
+    let mut fetcher_handles = Vec::with_capacity(config.watchers.len());
 
     {
         let mut startup_handles = Vec::with_capacity(config.watchers.len());
 
         for watcher in config.watchers {
-            let rx = rx.clone();
+            let rx = stop.clone();
+            let engine = engine.clone();
+            let events = service_events.subscribe();
+            let status = status.clone();
+            let metrics = metrics.clone();
+            let auditor = auditor.clone();
             let startup_handle = tokio::spawn(async move {
-                let fetcher = worker::Worker::new(watcher.clone(), rx);
-                if let Err(e) = fetcher.sync_secrets().await {
-                    let error_msg = format!("[{}] Failed to sync secrets: {}", &watcher.name, e);
-                    log::error!("{error_msg}");
-                    return Err(error_msg.into());
+                let (syncs, requests) = tokio::sync::mpsc::channel(SYNC_REQUESTS_CAPACITY);
+                let mut reconciler = Reconciler::new(watcher.clone(), engine, rx.clone(), requests);
+                reconciler.subscribe_service_events(events);
+                reconciler.report_status(status.clone());
+                reconciler.report_metrics(metrics.clone());
+                if let Some(auditor) = auditor {
+                    reconciler.record_audit(auditor);
+                }
+                if let Err(e) = reconciler.sync_at_startup().await {
+                    let e = e.context(format!("[{}] Failed to sync secrets", &watcher.name));
+                    log::error!("{e}");
+                    return Err(e);
                 }
 
-                Ok(fetcher)
+                let mut fetcher = Worker::new(watcher, rx, syncs);
+                fetcher.report_status(status);
+                fetcher.report_metrics(metrics);
+
+                Ok((reconciler, fetcher))
             });
 
             startup_handles.push(startup_handle);
         }
 
-        let fetchers: Vec<Result<crate::result::Result<Worker>, JoinError>> =
+        let started: Vec<Result<crate::result::Result<(Reconciler, Worker)>, JoinError>> =
             futures::future::join_all(startup_handles).await;
 
-        for fetcher_result in fetchers {
-            match fetcher_result {
-                Ok(Ok(mut fetcher)) => {
-                    let handle = tokio::spawn(async move {
-                        fetcher.run().await;
-                    });
-
-                    handles.push(handle);
+        for result in started {
+            match result {
+                Ok(Ok((mut reconciler, mut fetcher))) => {
+                    handles.push(tokio::spawn(async move {
+                        reconciler.run().await;
+                    }));
+                    fetcher_handles.push(tokio::spawn(async move {
+                        let name = fetcher.name().to_owned();
+                        fetcher
+                            .run()
+                            .await
+                            .map_err(|e| e.context(format!("Watcher {name}")))
+                    }));
                 }
                 Ok(Err(e)) => {
                     log::error!("Failed to start watcher: {e}");
+                    events_handle.abort();
+                    if let Some(server_handle) = &server_handle {
+                        server_handle.abort();
+                    }
                     return Err(e);
                 }
-                _ => {
-                    let error_msg = format!("Failed to start watcher: {:?}", fetcher_result);
-                    log::error!("{error_msg}");
-                    return Err(error_msg.into());
+                Err(e) => {
+                    let e = error::Error::internal("Failed to start watcher").with_source(e);
+                    log::error!("{e}");
+                    events_handle.abort();
+                    if let Some(server_handle) = &server_handle {
+                        server_handle.abort();
+                    }
+                    return Err(e);
                 }
             }
         }
     }
 
-    tokio::spawn(async move {
-        let mut sigint = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt())
-            .expect("Failed to create SIGINT signal handler");
-        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
-            .expect("Failed to create SIGTERM signal handler");
+    let fetched = futures::future::join_all(fetcher_handles).await;
+    futures::future::join_all(handles).await;
 
-        tokio::select! {
-            _ = sigint.recv() => {
-                log::info!("Received SIGINT, shutting down...");
-                tx.send(true).expect("Failed to send shutdown signal");
-            }
-            _ = sigterm.recv() => {
-                log::info!("Received SIGTERM, shutting down...");
-                tx.send(true).expect("Failed to send shutdown signal");
-            }
+    if *stop.borrow() {
+        let _ = events_handle.await;
+        if let Some(server_handle) = server_handle {
+            let _ = server_handle.await;
         }
-    });
+        return Ok(());
+    }
 
-    futures::future::join_all(handles).await;
+    // Every watcher gave up without a shutdown, e.g. on a revoked token.
+    // Nothing else would stop the event stream or the status server.
+    events_handle.abort();
+    if let Some(server_handle) = &server_handle {
+        server_handle.abort();
+    }
+
+    for result in fetched {
+        match result {
+            Ok(Err(e)) => return Err(e.context("All watchers stopped")),
+            Err(e) => return Err(error::Error::internal("Watcher failed").with_source(e)),
+            Ok(Ok(())) => {}
+        }
+    }
+
+    Err(error::Error::internal("All watchers stopped"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{wait_until, FakeDocker, FakeDoppler};
+
+    fn config(doppler: &FakeDoppler, docker: &FakeDocker, services: &[&str]) -> config::Config {
+        let mut config: config::Config = serde_json::from_value(serde_json::json!({
+            "docker_socket": docker.socket_path(),
+            "doppler_api_url": doppler.url(),
+            "watchers": [{
+                "name": "test",
+                "doppler_token": "dp.st.test",
+                "docker_services": services,
+                "debounce_seconds": 0,
+            }],
+        }))
+        .unwrap();
+
+        config::validate_config(&config).unwrap();
+        config::resolve_api_urls(&mut config);
+        config
+    }
+
+    #[tokio::test]
+    async fn test_startup_syncs_then_secrets_update_leads_to_one_update() {
+        let doppler = FakeDoppler::start(&[("API_KEY", "v1")]).await;
+        let docker = FakeDocker::swarm(&[
+            ("backend", &["API_KEY=v0"]),
+            ("sidekiq", &["API_KEY=v1"]),
+            ("unrelated", &["API_KEY=v0"]),
+        ]);
 
-    log::info!("Done.");
+        let (tx, rx) = tokio::sync::watch::channel(false);
+        let handle = tokio::spawn(run(config(&doppler, &docker, &["backend", "sidekiq"]), rx));
 
-    Ok(())
+        // Both services get the managed keys label, backend also gets the new value.
+        wait_until("startup sync", || docker.updates().len() == 2).await;
+        assert_eq!(docker.env("backend"), vec!["API_KEY=v1"]);
+        assert_eq!(docker.env("unrelated"), vec!["API_KEY=v0"]);
+
+        wait_until("watch stream", || doppler.live_streams() == 1).await;
+
+        doppler.set_secret("API_KEY", "v2");
+        doppler.send_event("secrets.update").await;
+
+        wait_until("secrets update", || docker.updates().len() == 4).await;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(docker.updates().len(), 4);
+        assert_eq!(docker.env("backend"), vec!["API_KEY=v2"]);
+        assert_eq!(docker.env("sidekiq"), vec!["API_KEY=v2"]);
+        assert_eq!(doppler.downloads(), 2);
+
+        tx.send(true).unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(5), handle)
+            .await
+            .expect("Workers did not stop")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_new_service_matching_pattern_is_synced() {
+        let doppler = FakeDoppler::start(&[("API_KEY", "v1")]).await;
+        let docker = FakeDocker::swarm(&[("staging-web", &[])]);
+
+        let (tx, rx) = tokio::sync::watch::channel(false);
+        let handle = tokio::spawn(run(config(&doppler, &docker, &["staging-*"]), rx));
+
+        wait_until("startup sync", || docker.updates().len() == 1).await;
+        wait_until("events stream", || docker.live_event_streams() == 1).await;
+
+        docker.create_service("staging-pr-42", &["ROLE=web"]);
+        docker.create_service("production-web", &["ROLE=web"]);
+
+        wait_until("new service sync", || docker.updates().len() == 2).await;
+        assert_eq!(docker.env("staging-pr-42"), vec!["ROLE=web", "API_KEY=v1"]);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(docker.env("production-web"), vec!["ROLE=web"]);
+        assert_eq!(docker.updates().len(), 2);
+
+        tx.send(true).unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(5), handle)
+            .await
+            .expect("Workers did not stop")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stack_deploy_overwrite_is_reverted_once() {
+        let doppler = FakeDoppler::start(&[("API_KEY", "v1")]).await;
+        let docker = FakeDocker::swarm(&[("backend", &["API_KEY=v1"])]);
+
+        let (tx, rx) = tokio::sync::watch::channel(false);
+        let handle = tokio::spawn(run(config(&doppler, &docker, &["backend"]), rx));
+
+        wait_until("startup sync", || docker.updates().len() == 1).await;
+        wait_until("events stream", || docker.live_event_streams() == 1).await;
+
+        docker.set_env("backend", &["API_KEY=from-stack-file"]);
+
+        wait_until("re-apply", || docker.updates().len() == 2).await;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(docker.updates().len(), 2);
+        assert_eq!(docker.env("backend"), vec!["API_KEY=v1"]);
+        assert_eq!(doppler.downloads(), 1);
+
+        tx.send(true).unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(5), handle)
+            .await
+            .expect("Workers did not stop")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_startup_fails_on_unknown_service() {
+        let doppler = FakeDoppler::start(&[("API_KEY", "v1")]).await;
+        let docker = FakeDocker::swarm(&[("backend", &[])]);
+
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let result = run(config(&doppler, &docker, &["missing"]), rx).await;
+
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "[test] Failed to sync secrets: Failed to list services: Configuration error: service missing does not exist"
+        );
+        assert!(docker.updates().is_empty());
+    }
 }
@@ -0,0 +1,291 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+use crate::{docker::PlannedChange, reconciler::SyncReason, watch::WatchEvent};
+
+/// Prometheus metrics for watchers and syncs, served on `/metrics`. Each
+/// instance has its own registry, so a default `Metrics` is simply never
+/// scraped.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    watch_connections: IntCounterVec,
+    watch_failures: IntCounterVec,
+    watch_events: IntCounterVec,
+    syncs: IntCounterVec,
+    sync_failures: IntCounterVec,
+    drift: IntCounterVec,
+    services_updated: IntCounterVec,
+    env_changes: IntCounterVec,
+    doppler_fetch_seconds: HistogramVec,
+    docker_update_seconds: HistogramVec,
+    seconds_since_ping: GaugeVec,
+    last_ping: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl std::fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn counter(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    let counter = IntCounterVec::new(Opts::new(name, help), labels).expect("Invalid metric");
+    registry
+        .register(Box::new(counter.clone()))
+        .expect("Metric registered twice");
+    counter
+}
+
+fn histogram(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> HistogramVec {
+    let histogram = HistogramVec::new(
+        HistogramOpts::new(name, help).buckets(vec![
+            0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+        ]),
+        labels,
+    )
+    .expect("Invalid metric");
+    registry
+        .register(Box::new(histogram.clone()))
+        .expect("Metric registered twice");
+    histogram
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("doppler_swarm".to_owned()), None)
+            .expect("Invalid metrics prefix");
+
+        let seconds_since_ping = GaugeVec::new(
+            Opts::new(
+                "seconds_since_last_ping",
+                "Seconds since the watch stream last received a ping",
+            ),
+            &["watcher"],
+        )
+        .expect("Invalid metric");
+        registry
+            .register(Box::new(seconds_since_ping.clone()))
+            .expect("Metric registered twice");
+
+        Self {
+            watch_connections: counter(
+                &registry,
+                "watch_connections_total",
+                "Watch streams opened",
+                &["watcher"],
+            ),
+            watch_failures: counter(
+                &registry,
+                "watch_failures_total",
+                "Watch streams that failed to open or broke",
+                &["watcher"],
+            ),
+            watch_events: counter(
+                &registry,
+                "watch_events_total",
+                "Events received on the watch stream",
+                &["watcher", "event"],
+            ),
+            syncs: counter(
+                &registry,
+                "syncs_total",
+                "Syncs of all matched services",
+                &["watcher", "reason"],
+            ),
+            sync_failures: counter(
+                &registry,
+                "sync_failures_total",
+                "Syncs of all matched services that failed",
+                &["watcher", "reason"],
+            ),
+            drift: counter(
+                &registry,
+                "drift_detected_total",
+                "Services whose env no longer matched Doppler during a reconciliation",
+                &["watcher", "service"],
+            ),
+            services_updated: counter(
+                &registry,
+                "services_updated_total",
+                "Docker service updates",
+                &["watcher", "service"],
+            ),
+            env_changes: counter(
+                &registry,
+                "env_changes_total",
+                "Env keys added, changed or removed by service updates",
+                &["watcher", "service", "change"],
+            ),
+            doppler_fetch_seconds: histogram(
+                &registry,
+                "doppler_fetch_duration_seconds",
+                "Time to download secrets from Doppler",
+                &["watcher"],
+            ),
+            docker_update_seconds: histogram(
+                &registry,
+                "docker_update_duration_seconds",
+                "Time to update a docker service",
+                &["watcher", "service"],
+            ),
+            seconds_since_ping,
+            last_ping: Arc::default(),
+            registry,
+        }
+    }
+
+    pub fn watch_connected(&self, watcher: &str) {
+        self.watch_connections.with_label_values(&[watcher]).inc();
+    }
+
+    pub fn watch_failed(&self, watcher: &str) {
+        self.watch_failures.with_label_values(&[watcher]).inc();
+    }
+
+    pub fn watch_event(&self, watcher: &str, event: WatchEvent) {
+        let label = match event {
+            WatchEvent::Ping => "ping",
+            WatchEvent::Connected => "connected",
+            WatchEvent::SecretsUpdate => "secrets_update",
+            WatchEvent::Unknown => "unknown",
+        };
+        self.watch_events.with_label_values(&[watcher, label]).inc();
+
+        if event == WatchEvent::Ping {
+            self.last_ping
+                .lock()
+                .unwrap()
+                .insert(watcher.to_owned(), Instant::now());
+        }
+    }
+
+    pub fn sync_finished(&self, watcher: &str, reason: SyncReason, ok: bool) {
+        let reason = reason.to_string().replace(' ', "_");
+        self.syncs.with_label_values(&[watcher, &reason]).inc();
+
+        if !ok {
+            self.sync_failures
+                .with_label_values(&[watcher, &reason])
+                .inc();
+        }
+    }
+
+    pub fn drift_detected(&self, watcher: &str, service: &str) {
+        self.drift.with_label_values(&[watcher, service]).inc();
+    }
+
+    pub fn service_updated(&self, watcher: &str, service: &str, changes: &[PlannedChange]) {
+        self.services_updated
+            .with_label_values(&[watcher, service])
+            .inc();
+
+        for change in changes {
+            let change = match change {
+                PlannedChange::Add { .. } => "added",
+                PlannedChange::Change { .. } => "changed",
+                PlannedChange::Remove { .. } => "removed",
+            };
+            self.env_changes
+                .with_label_values(&[watcher, service, change])
+                .inc();
+        }
+    }
+
+    pub fn doppler_fetched(&self, watcher: &str, took: Duration) {
+        self.doppler_fetch_seconds
+            .with_label_values(&[watcher])
+            .observe(took.as_secs_f64());
+    }
+
+    /// Time the Docker API took to accept an update, whether or not its
+    /// rolling update converges later.
+    pub fn docker_updated(&self, watcher: &str, service: &str, took: Duration) {
+        self.docker_update_seconds
+            .with_label_values(&[watcher, service])
+            .observe(took.as_secs_f64());
+    }
+
+    /// Renders every metric in the Prometheus text format.
+    pub fn render(&self) -> String {
+        for (watcher, at) in self.last_ping.lock().unwrap().iter() {
+            self.seconds_since_ping
+                .with_label_values(&[watcher])
+                .set(at.elapsed().as_secs_f64());
+        }
+
+        let mut buffer = vec![];
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            log::error!("Failed to encode metrics: {e}");
+        }
+
+        String::from_utf8_lossy(&buffer).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::SecretValue;
+
+    #[test]
+    fn test_render_counts_syncs_and_env_changes() {
+        let metrics = Metrics::new();
+        metrics.sync_finished("prod", SyncReason::Startup, true);
+        metrics.sync_finished("prod", SyncReason::Reconcile, false);
+        metrics.drift_detected("prod", "backend");
+        metrics.service_updated(
+            "prod",
+            "backend",
+            &[
+                PlannedChange::Add {
+                    name: "A".to_owned(),
+                    value: SecretValue::from("1"),
+                },
+                PlannedChange::Remove {
+                    name: "B".to_owned(),
+                    old_value: SecretValue::from("2"),
+                },
+            ],
+        );
+        metrics.docker_updated("prod", "backend", Duration::from_millis(300));
+
+        let text = metrics.render();
+        assert!(text.contains(r#"doppler_swarm_syncs_total{reason="startup",watcher="prod"} 1"#));
+        assert!(text
+            .contains(r#"doppler_swarm_sync_failures_total{reason="reconcile",watcher="prod"} 1"#));
+        assert!(text
+            .contains(r#"doppler_swarm_drift_detected_total{service="backend",watcher="prod"} 1"#));
+        assert!(text.contains(
+            r#"doppler_swarm_env_changes_total{change="added",service="backend",watcher="prod"} 1"#
+        ));
+        assert!(text.contains(
+            r#"doppler_swarm_docker_update_duration_seconds_count{service="backend",watcher="prod"} 1"#
+        ));
+    }
+
+    #[test]
+    fn test_seconds_since_last_ping_appears_after_first_ping() {
+        let metrics = Metrics::new();
+        metrics.watch_event("prod", WatchEvent::Connected);
+        assert!(!metrics.render().contains("seconds_since_last_ping{"));
+
+        metrics.watch_event("prod", WatchEvent::Ping);
+        let text = metrics.render();
+        assert!(text.contains(r#"doppler_swarm_seconds_since_last_ping{watcher="prod"}"#));
+        assert!(text.contains(r#"doppler_swarm_watch_events_total{event="ping",watcher="prod"} 1"#));
+    }
+}
@@ -0,0 +1,344 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    config::{self, Webhook, WebhookFormat},
+    error::Error,
+};
+
+/// Deliveries per webhook before a notification is dropped.
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Wait before the first retry, doubled for each later one.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Ok,
+    Failed,
+    /// Sent by `doppler-swarm notify-test`.
+    Test,
+}
+
+/// What a sync did, as posted to webhooks in the generic format. Only key
+/// names are included, never values.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Notification {
+    pub watcher: String,
+    /// Why the sync ran, e.g. `secrets update`.
+    pub reason: String,
+    pub outcome: Outcome,
+    /// Services the sync updated.
+    pub services: Vec<String>,
+    pub changed_keys: Vec<String>,
+    pub error: Option<String>,
+    pub sync_id: Option<String>,
+}
+
+impl Notification {
+    /// One line for chat, e.g. `:white_check_mark: *production*: updated
+    /// backend (secrets update), changed API_KEY`.
+    pub fn text(&self) -> String {
+        match self.outcome {
+            Outcome::Ok => {
+                let mut text = format!(
+                    ":white_check_mark: *{}*: updated {} ({})",
+                    self.watcher,
+                    self.services.join(", "),
+                    self.reason
+                );
+                if !self.changed_keys.is_empty() {
+                    text.push_str(&format!(", changed {}", self.changed_keys.join(", ")));
+                }
+                text
+            }
+            Outcome::Failed => format!(
+                ":x: *{}*: sync failed ({}): {}",
+                self.watcher,
+                self.reason,
+                self.error.as_deref().unwrap_or("unknown error")
+            ),
+            Outcome::Test => format!(
+                ":bell: *{}*: test notification from doppler-swarm",
+                self.watcher
+            ),
+        }
+    }
+
+    fn body(&self, format: WebhookFormat) -> serde_json::Value {
+        match format {
+            WebhookFormat::Generic => json!(self),
+            WebhookFormat::Slack => json!({ "text": self.text() }),
+        }
+    }
+}
+
+/// Posts notifications to the webhooks of one watcher.
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    http: reqwest::Client,
+    webhooks: Vec<Webhook>,
+    retry_delay: Duration,
+}
+
+impl Notifier {
+    pub fn new(webhooks: Vec<Webhook>) -> Self {
+        let http = reqwest::ClientBuilder::new()
+            .use_rustls_tls()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("Cannot build http client");
+
+        Self {
+            http,
+            webhooks,
+            retry_delay: RETRY_DELAY,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.webhooks.is_empty()
+    }
+
+    /// Delivers to every webhook, retrying each one on its own. Returns the
+    /// first delivery that failed for good.
+    pub async fn send(&self, notification: &Notification) -> crate::result::Result<()> {
+        let mut result = Ok(());
+
+        for webhook in &self.webhooks {
+            if let Err(e) = self.deliver(webhook, notification).await {
+                log::warn!(
+                    watcher = notification.watcher.as_str();
+                    "Notification not delivered: {e}"
+                );
+                result = result.and(Err(e));
+            }
+        }
+
+        result
+    }
+
+    async fn deliver(
+        &self,
+        webhook: &Webhook,
+        notification: &Notification,
+    ) -> crate::result::Result<()> {
+        let body = notification.body(webhook.format);
+        let target = redact(&webhook.url);
+        let mut attempt = 1;
+
+        loop {
+            let error = match self.http.post(&webhook.url).json(&body).send().await {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response) => {
+                    let status = response.status();
+                    let error = Error::webhook(format!("Webhook {target} returned {status}"));
+                    // Retrying the same payload can't fix a client error.
+                    if status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS
+                    {
+                        return Err(error);
+                    }
+                    error
+                }
+                Err(e) => Error::webhook(format!("Failed to reach webhook {target}"))
+                    .with_source(e.without_url()),
+            };
+
+            if attempt == MAX_ATTEMPTS {
+                return Err(error.context(format!("Gave up after {MAX_ATTEMPTS} attempts")));
+            }
+
+            let delay = self.retry_delay * 2u32.pow(attempt - 1);
+            log::debug!(
+                watcher = notification.watcher.as_str();
+                "{error}, retrying in {:.1}s",
+                delay.as_secs_f64()
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// Keeps the scheme and host of a webhook URL. The path of e.g. a Slack
+/// webhook is a credential and must not end up in logs.
+fn redact(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(url) => format!("{}://{}", url.scheme(), url.host_str().unwrap_or_default()),
+        Err(_) => "<invalid url>".to_owned(),
+    }
+}
+
+/// Sends a test notification for every watcher with webhooks, for
+/// `doppler-swarm notify-test`. With `url`, every webhook is pointed there
+/// instead, e.g. at a local receiver, keeping its format.
+pub async fn send_test(config: &config::Config, url: Option<&str>) -> crate::result::Result<()> {
+    if let Some(url) = url {
+        config::validate_webhook_url(url)?;
+    }
+
+    let mut sent = 0;
+
+    for watcher in &config.watchers {
+        let mut webhooks = watcher.notifications.clone();
+        if let Some(url) = url {
+            for webhook in &mut webhooks {
+                webhook.url = url.to_owned();
+            }
+        }
+
+        if webhooks.is_empty() {
+            continue;
+        }
+
+        let notification = Notification {
+            watcher: watcher.name.clone(),
+            reason: "test".to_owned(),
+            outcome: Outcome::Test,
+            services: watcher.docker_services.clone(),
+            changed_keys: vec![],
+            error: None,
+            sync_id: None,
+        };
+
+        Notifier::new(webhooks).send(&notification).await?;
+        log::info!(
+            watcher = watcher.name.as_str();
+            "Test notification delivered"
+        );
+        sent += 1;
+    }
+
+    if sent == 0 {
+        return Err(Error::config(
+            "Configuration error: no watcher has notifications configured",
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakeWebhook;
+
+    fn notification(outcome: Outcome) -> Notification {
+        Notification {
+            watcher: "production".to_owned(),
+            reason: "secrets update".to_owned(),
+            outcome,
+            services: vec!["backend".to_owned(), "worker".to_owned()],
+            changed_keys: vec!["API_KEY".to_owned()],
+            error: (outcome == Outcome::Failed).then(|| "Doppler is down".to_owned()),
+            sync_id: None,
+        }
+    }
+
+    fn notifier(webhooks: &[(&FakeWebhook, WebhookFormat)]) -> Notifier {
+        let mut notifier = Notifier::new(
+            webhooks
+                .iter()
+                .map(|(webhook, format)| Webhook {
+                    url: webhook.url(),
+                    format: *format,
+                })
+                .collect(),
+        );
+        notifier.retry_delay = Duration::from_millis(10);
+        notifier
+    }
+
+    #[tokio::test]
+    async fn test_generic_and_slack_formats() {
+        let generic = FakeWebhook::start().await;
+        let slack = FakeWebhook::start().await;
+        let notifier = notifier(&[
+            (&generic, WebhookFormat::Generic),
+            (&slack, WebhookFormat::Slack),
+        ]);
+
+        notifier.send(&notification(Outcome::Ok)).await.unwrap();
+
+        assert_eq!(
+            generic.bodies(),
+            [json!({
+                "watcher": "production",
+                "reason": "secrets update",
+                "outcome": "ok",
+                "services": ["backend", "worker"],
+                "changed_keys": ["API_KEY"],
+                "error": null,
+                "sync_id": null,
+            })]
+        );
+        assert_eq!(
+            slack.bodies(),
+            [json!({
+                "text": ":white_check_mark: *production*: updated backend, worker (secrets update), changed API_KEY"
+            })]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_delivery_is_retried() {
+        let webhook = FakeWebhook::start().await;
+        webhook.queue_status(503);
+        webhook.queue_status(500);
+
+        notifier(&[(&webhook, WebhookFormat::Slack)])
+            .send(&notification(Outcome::Failed))
+            .await
+            .unwrap();
+
+        let bodies = webhook.bodies();
+        assert_eq!(bodies.len(), 3);
+        assert_eq!(
+            bodies[2]["text"],
+            ":x: *production*: sync failed (secrets update): Doppler is down"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_error_is_not_retried() {
+        let webhook = FakeWebhook::start().await;
+        webhook.queue_status(404);
+
+        let error = notifier(&[(&webhook, WebhookFormat::Generic)])
+            .send(&notification(Outcome::Ok))
+            .await
+            .unwrap_err();
+
+        assert_eq!(webhook.bodies().len(), 1);
+        assert!(matches!(error, Error::Webhook { .. }));
+        assert_eq!(
+            error.to_string(),
+            "Webhook http://127.0.0.1 returned 404 Not Found"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_test_targets_local_receiver() {
+        let receiver = FakeWebhook::start().await;
+        let config: config::Config = serde_json::from_str(
+            r#"{"watchers": [
+                {"name": "a", "doppler_token": "t", "docker_services": ["s1"],
+                 "notifications": [{"url": "https://hooks.slack.com/services/T/B/X", "format": "slack"}]},
+                {"name": "b", "doppler_token": "t", "docker_services": ["s2"]}
+            ]}"#,
+        )
+        .unwrap();
+
+        send_test(&config, Some(&receiver.url())).await.unwrap();
+
+        assert_eq!(
+            receiver.bodies(),
+            [json!({ "text": ":bell: *a*: test notification from doppler-swarm" })]
+        );
+    }
+}
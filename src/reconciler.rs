@@ -0,0 +1,1405 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    time::Duration,
+};
+
+use crate::{
+    audit::Auditor,
+    config,
+    docker::{PlannedChange, Rollout, UpdateOutcome},
+    engine::Engine,
+    error::Error,
+    events::ServiceEvent,
+    metrics::Metrics,
+    notify::{Notification, Notifier, Outcome},
+    secrets::{fetch_secrets, EnvVars},
+    status::Status,
+};
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc,
+    },
+    time::Instant,
+};
+
+/// How many times a service update is re-read and retried when the service
+/// spec changes between inspection and update.
+const MAX_UPDATE_ATTEMPTS: u32 = 3;
+
+/// Base wait before re-reading a service after a version conflict, so a
+/// racing deploy has time to settle. Jittered between half and one and a
+/// half times this.
+const VERSION_CONFLICT_DELAY: Duration = Duration::from_millis(250);
+
+/// A burst of `secrets.update` events delays the sync by at most this many
+/// debounce windows, so a steady trickle of edits can't postpone it forever.
+const MAX_DEBOUNCE_WINDOWS: u32 = 4;
+
+/// A service overwritten more often than this within `REAPPLY_WINDOW` is
+/// left alone: something else is fighting us for its env.
+const MAX_REAPPLIES: usize = 3;
+const REAPPLY_WINDOW: Duration = Duration::from_secs(300);
+
+/// How long to wait before retrying a Doppler-driven sync that failed.
+const SYNC_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// How often a startup sync rate limited by Doppler is retried before the
+/// watcher fails to start.
+const MAX_STARTUP_RATE_LIMIT_RETRIES: u32 = 3;
+
+/// What the watch stream asks the reconciler to do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncRequest {
+    /// Doppler reported a change to the watched config. The sync logs
+    /// under `sync_id`, which the stream already logged the event with.
+    SecretsUpdate { sync_id: String },
+}
+
+/// `secrets.update` events waiting for the debounce window to pass.
+#[derive(Debug, Clone)]
+struct PendingUpdate {
+    first: Instant,
+    due: Instant,
+    events: u32,
+    /// Id of the first event, shared by the coalesced ones.
+    sync_id: String,
+}
+
+/// Why a sync runs. Drift found by a periodic reconciliation is reported
+/// separately from changes pushed by Doppler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncReason {
+    Startup,
+    SecretsUpdate,
+    Reconcile,
+    ServiceCreated,
+    ServiceOverwritten,
+}
+
+impl std::fmt::Display for SyncReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Startup => "startup",
+            Self::SecretsUpdate => "secrets update",
+            Self::Reconcile => "reconcile",
+            Self::ServiceCreated => "service created",
+            Self::ServiceOverwritten => "service overwritten",
+        })
+    }
+}
+
+/// Owns everything that updates a watcher's services: the queue of
+/// Doppler-driven syncs, periodic reconciliation and Docker service events.
+/// It runs as its own task so a slow rolling update never stalls the watch
+/// stream.
+#[derive(Debug)]
+pub struct Reconciler {
+    watcher: config::Watcher,
+    engine: Engine,
+    http: reqwest::Client,
+    stop: tokio::sync::watch::Receiver<bool>,
+    wanna_stop: bool,
+    /// Signals from the watch stream. Closed once the stream gives up.
+    requests: mpsc::Receiver<SyncRequest>,
+    /// When the next periodic reconciliation is due, if enabled.
+    next_reconcile: Option<Instant>,
+    debounce: Duration,
+    pending_update: Option<PendingUpdate>,
+    /// Docker service events, to sync services created or overwritten
+    /// after startup.
+    service_events: Option<broadcast::Receiver<ServiceEvent>>,
+    /// The Doppler secrets of the last sync, re-applied to services that
+    /// lose them.
+    last_applied: Option<EnvVars>,
+    /// When each service was last re-applied, within `REAPPLY_WINDOW`.
+    reapplied: HashMap<String, Vec<Instant>>,
+    /// Services whose rolling update failed, with the Doppler secrets it
+    /// tried to apply. They are left alone until those secrets change.
+    failed_rollouts: HashMap<String, EnvVars>,
+    status: Status,
+    metrics: Metrics,
+    /// Records every applied update when the audit log is configured.
+    auditor: Option<Auditor>,
+    notifier: Notifier,
+    /// What the sync in progress changed so far, for its notification.
+    applied: Applied,
+}
+
+/// Services a sync updated and the env keys it changed.
+#[derive(Debug, Default)]
+struct Applied {
+    services: Vec<String>,
+    keys: BTreeSet<String>,
+}
+
+async fn next_service_event(
+    events: &mut Option<broadcast::Receiver<ServiceEvent>>,
+) -> Result<ServiceEvent, RecvError> {
+    match events {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+pub fn should_update_docker_service(
+    doppler_secrets: &EnvVars,
+    docker_secrets: &EnvVars,
+    managed_keys: &HashSet<String>,
+) -> bool {
+    doppler_secrets
+        .iter()
+        .any(|(name, value)| docker_secrets.get(name) != Some(value))
+        || managed_keys.len() != doppler_secrets.len()
+        || managed_keys
+            .iter()
+            .any(|name| !doppler_secrets.contains_key(name))
+}
+
+impl Reconciler {
+    pub fn new(
+        watcher: config::Watcher,
+        engine: Engine,
+        stop: tokio::sync::watch::Receiver<bool>,
+        requests: mpsc::Receiver<SyncRequest>,
+    ) -> Self {
+        let http = reqwest::ClientBuilder::new()
+            .use_rustls_tls()
+            .connect_timeout(std::time::Duration::from_secs(10))
+            .build()
+            .expect("Cannot build http client");
+
+        // Shutdown may have been requested before this reconciler was created.
+        let wanna_stop = *stop.borrow();
+        let next_reconcile = watcher
+            .reconcile_interval()
+            .map(|interval| Instant::now() + interval);
+        let debounce = watcher.debounce();
+        let notifier = Notifier::new(watcher.notifications.clone());
+
+        Self {
+            watcher,
+            engine,
+            http,
+            stop,
+            wanna_stop,
+            requests,
+            next_reconcile,
+            debounce,
+            pending_update: None,
+            service_events: None,
+            last_applied: None,
+            reapplied: HashMap::new(),
+            failed_rollouts: HashMap::new(),
+            status: Status::default(),
+            metrics: Metrics::default(),
+            auditor: None,
+            notifier,
+            applied: Applied::default(),
+        }
+    }
+
+    pub fn subscribe_service_events(&mut self, events: broadcast::Receiver<ServiceEvent>) {
+        self.service_events = Some(events);
+    }
+
+    pub fn report_status(&mut self, status: Status) {
+        self.status = status;
+    }
+
+    pub fn report_metrics(&mut self, metrics: Metrics) {
+        self.metrics = metrics;
+    }
+
+    pub fn record_audit(&mut self, auditor: Auditor) {
+        self.auditor = Some(auditor);
+    }
+
+    /// Handles sync requests, timers and service events one at a time until
+    /// `stop` flips to true or the watch stream goes away. A sync in
+    /// progress is finished before stopping.
+    pub async fn run(&mut self) {
+        while !self.wanna_stop {
+            let next_reconcile = self.next_reconcile;
+            let pending_update = self.pending_update.as_ref().map(|pending| pending.due);
+
+            tokio::select! {
+                _ = self.stop.changed() => {
+                    self.wanna_stop = *self.stop.borrow();
+                }
+                request = self.requests.recv() => match request {
+                    Some(SyncRequest::SecretsUpdate { sync_id }) => {
+                        self.queue_secrets_update(sync_id);
+                    }
+                    None => {
+                        log::info!(
+                            watcher = self.watcher.name.as_str();
+                            "Watch stream stopped, stopping reconciler"
+                        );
+                        return;
+                    }
+                },
+                _ = tokio::time::sleep_until(next_reconcile.unwrap_or_else(Instant::now)), if next_reconcile.is_some() => {
+                    self.reconcile().await;
+                }
+                _ = tokio::time::sleep_until(pending_update.unwrap_or_else(Instant::now)), if pending_update.is_some() => {
+                    self.sync_pending_update().await;
+                }
+                event = next_service_event(&mut self.service_events) => {
+                    self.handle_service_event(event).await;
+                }
+            }
+        }
+    }
+
+    /// Re-syncs every service to undo drift, e.g. a manual
+    /// `docker service update --env-add`. A failure is logged and the next
+    /// reconciliation tries again.
+    async fn reconcile(&mut self) {
+        log::info!(watcher = self.watcher.name.as_str(); "Reconciling services");
+
+        // A failure is logged by `sync_secrets`.
+        let _ = self.sync_secrets(SyncReason::Reconcile).await;
+
+        self.schedule_reconcile();
+    }
+
+    /// Pushes the next reconciliation a full interval out, since every
+    /// service was just synced.
+    fn schedule_reconcile(&mut self) {
+        self.next_reconcile = self
+            .watcher
+            .reconcile_interval()
+            .map(|interval| Instant::now() + interval);
+    }
+
+    async fn handle_service_event(&mut self, event: Result<ServiceEvent, RecvError>) {
+        let event = match event {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                log::warn!(
+                    watcher = self.watcher.name.as_str();
+                    "Missed {} docker events, reconciling all services",
+                    missed
+                );
+                self.reconcile().await;
+                return;
+            }
+            Err(RecvError::Closed) => {
+                self.service_events = None;
+                return;
+            }
+        };
+
+        let Some(service_name) = event.service_name() else {
+            return;
+        };
+
+        if !crate::docker::matches_watcher(&self.watcher, service_name) {
+            return;
+        }
+
+        let reason = match event.action.as_str() {
+            "create" => SyncReason::ServiceCreated,
+            "update" => SyncReason::ServiceOverwritten,
+            _ => return,
+        };
+
+        crate::logging::with_sync_id(async {
+            let result = match reason {
+                SyncReason::ServiceCreated => {
+                    log::info!(
+                        watcher = self.watcher.name.as_str(), service = service_name;
+                        "New service matches watcher, syncing"
+                    );
+                    self.sync_new_service(service_name).await
+                }
+                _ => self.reapply_if_overwritten(service_name).await,
+            };
+
+            if let Err(e) = &result {
+                log::error!(
+                    watcher = self.watcher.name.as_str(), service = service_name, outcome = "failed";
+                    "Failed to sync service after docker {} event: {}",
+                    event.action,
+                    e
+                );
+            }
+
+            self.notify(reason, &result);
+        })
+        .await;
+    }
+
+    async fn download_secrets(&self) -> crate::result::Result<EnvVars> {
+        let started = Instant::now();
+        let secrets = fetch_secrets(
+            &self.http,
+            self.watcher.api_url(),
+            &self.watcher.doppler_token,
+        )
+        .await;
+        self.metrics
+            .doppler_fetched(&self.watcher.name, started.elapsed());
+
+        secrets
+    }
+
+    async fn sync_new_service(&mut self, service_name: &str) -> crate::result::Result<()> {
+        let doppler_secrets = self.download_secrets().await?;
+        self.last_applied = Some(doppler_secrets.clone());
+
+        self.sync_service(service_name, &doppler_secrets, SyncReason::ServiceCreated)
+            .await
+    }
+
+    /// Handles a service update, e.g. `docker stack deploy` replacing the
+    /// spec and dropping our env vars, by re-applying the last Doppler
+    /// snapshot without asking Doppler again. Our own updates emit events
+    /// too; they match the snapshot and are ignored, so they can't loop.
+    async fn reapply_if_overwritten(&mut self, service_name: &str) -> crate::result::Result<()> {
+        let Some(snapshot) = self.last_applied.clone() else {
+            return Ok(());
+        };
+
+        let service = crate::docker::inspect_service(&self.engine, service_name).await?;
+        let docker_secrets = crate::docker::get_current_env_vars(&service)
+            .map_err(|e| e.context("Failed to get current env vars"))?;
+
+        if !should_update_docker_service(
+            &snapshot,
+            &docker_secrets,
+            &crate::docker::managed_keys(&service),
+        ) {
+            log::debug!(
+                watcher = self.watcher.name.as_str(), service = service_name;
+                "Service env still matches Doppler"
+            );
+            return Ok(());
+        }
+
+        if self.rollout_failed_before(service_name, &snapshot) {
+            log::debug!(
+                watcher = self.watcher.name.as_str(), service = service_name;
+                "Service was rolled back from the last Doppler snapshot, not re-applying"
+            );
+            return Ok(());
+        }
+
+        if !self.allow_reapply(service_name) {
+            log::error!(
+                watcher = self.watcher.name.as_str(), service = service_name;
+                "Service env keeps being overwritten, not re-applying more than {} times in {}s",
+                MAX_REAPPLIES,
+                REAPPLY_WINDOW.as_secs()
+            );
+            return Ok(());
+        }
+
+        log::warn!(
+            watcher = self.watcher.name.as_str(), service = service_name;
+            "Service env was overwritten, re-applying the last Doppler snapshot"
+        );
+
+        self.sync_service(service_name, &snapshot, SyncReason::ServiceOverwritten)
+            .await
+    }
+
+    /// Whether applying `doppler_secrets` to the service already failed its
+    /// rolling update. Different secrets clear the mark.
+    fn rollout_failed_before(&mut self, service_name: &str, doppler_secrets: &EnvVars) -> bool {
+        match self.failed_rollouts.get(service_name) {
+            Some(failed) if failed == doppler_secrets => true,
+            Some(_) => {
+                self.failed_rollouts.remove(service_name);
+                self.report_failed_rollouts();
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn report_failed_rollouts(&self) {
+        let mut services: Vec<String> = self.failed_rollouts.keys().cloned().collect();
+        services.sort();
+        self.status.failed_rollouts(&self.watcher.name, services);
+    }
+
+    /// Limits how often a service is re-applied, so we don't fight forever
+    /// with something else that keeps rewriting it.
+    fn allow_reapply(&mut self, service_name: &str) -> bool {
+        self.allow_reapply_at(service_name, Instant::now())
+    }
+
+    fn allow_reapply_at(&mut self, service_name: &str, now: Instant) -> bool {
+        // Forget services that went quiet, e.g. ones that were removed.
+        self.reapplied.retain(|_, recent| {
+            recent.retain(|at| now.duration_since(*at) < REAPPLY_WINDOW);
+            !recent.is_empty()
+        });
+
+        let recent = self.reapplied.entry(service_name.to_owned()).or_default();
+        if recent.len() >= MAX_REAPPLIES {
+            return false;
+        }
+
+        recent.push(now);
+        true
+    }
+
+    /// The first sync of a watcher. When Doppler rate limits it, waits as
+    /// asked and tries again, unless `stop` flips first.
+    pub async fn sync_at_startup(&mut self) -> crate::result::Result<()> {
+        let mut retries = 0;
+
+        loop {
+            let result = self.sync_secrets(SyncReason::Startup).await;
+
+            let retry_after = match &result {
+                Err(e @ Error::DopplerRateLimited { .. })
+                    if retries < MAX_STARTUP_RATE_LIMIT_RETRIES && !self.wanna_stop =>
+                {
+                    e.retry_after().unwrap_or(SYNC_RETRY_DELAY)
+                }
+                _ => return result,
+            };
+            retries += 1;
+
+            log::warn!(
+                watcher = self.watcher.name.as_str();
+                "Rate limited by Doppler, retrying startup sync in {}s",
+                retry_after.as_secs()
+            );
+
+            tokio::select! {
+                _ = self.stop.changed() => {
+                    self.wanna_stop = *self.stop.borrow();
+                    return result;
+                }
+                _ = tokio::time::sleep(retry_after) => {}
+            }
+        }
+    }
+
+    /// Syncs every matched service and records the outcome in the status.
+    pub async fn sync_secrets(&mut self, reason: SyncReason) -> crate::result::Result<()> {
+        crate::logging::with_sync_id(self.sync_in_scope(reason)).await
+    }
+
+    /// `sync_secrets` under the sync id the caller already scoped.
+    async fn sync_in_scope(&mut self, reason: SyncReason) -> crate::result::Result<()> {
+        let result = self.sync_all_services(reason).await;
+        self.status
+            .sync_finished(&self.watcher.name, reason, &result);
+        self.metrics
+            .sync_finished(&self.watcher.name, reason, result.is_ok());
+        self.log_outcome(reason, &result);
+        self.notify(reason, &result);
+
+        result
+    }
+
+    fn log_outcome(&self, reason: SyncReason, result: &crate::result::Result<()>) {
+        let reason = reason.to_string();
+
+        match result {
+            Ok(()) => log::info!(
+                watcher = self.watcher.name.as_str(), reason = reason.as_str(), outcome = "ok";
+                "Sync finished ({})",
+                reason
+            ),
+            Err(e) => log::error!(
+                watcher = self.watcher.name.as_str(), reason = reason.as_str(), outcome = "failed";
+                "Sync failed ({}): {}",
+                reason,
+                e
+            ),
+        }
+    }
+
+    async fn sync_all_services(&mut self, reason: SyncReason) -> crate::result::Result<()> {
+        let doppler_secrets = self.download_secrets().await?;
+        self.last_applied = Some(doppler_secrets.clone());
+
+        let services = crate::docker::list_services(&self.engine, &self.watcher).await?;
+        self.status.set_services(&self.watcher.name, &services);
+
+        // Removed services can't be rolled back anymore.
+        self.failed_rollouts
+            .retain(|service, _| services.contains(service));
+        self.report_failed_rollouts();
+
+        for service in &services {
+            self.sync_service(service, &doppler_secrets, reason)
+                .await
+                .map_err(|e| e.context(format!("Failed to sync service {service}")))?;
+        }
+
+        // Skipped services still fail the sync, so it shows in the status.
+        let skipped: Vec<&str> = services
+            .iter()
+            .filter(|service| self.failed_rollouts.contains_key(*service))
+            .map(String::as_str)
+            .collect();
+        if !skipped.is_empty() {
+            return Err(Error::docker_rollout(format!(
+                "Rolling update failed for {}, waiting for a change in Doppler",
+                skipped.join(", ")
+            )));
+        }
+
+        Ok(())
+    }
+
+    async fn sync_service(
+        &mut self,
+        service_name: &str,
+        doppler_secrets: &EnvVars,
+        reason: SyncReason,
+    ) -> crate::result::Result<()> {
+        if self.rollout_failed_before(service_name, doppler_secrets) {
+            log::warn!(
+                watcher = self.watcher.name.as_str(), service = service_name, outcome = "skipped";
+                "Skipping service: its rolling update of these secrets failed, waiting for a change in Doppler"
+            );
+            return Ok(());
+        }
+
+        for attempt in 1..=MAX_UPDATE_ATTEMPTS {
+            let service = crate::docker::inspect_service(&self.engine, service_name).await?;
+
+            let docker_secrets = crate::docker::get_current_env_vars(&service)
+                .map_err(|e| e.context("Failed to get current env vars"))?;
+
+            let managed_keys = crate::docker::managed_keys(&service);
+
+            if !should_update_docker_service(doppler_secrets, &docker_secrets, &managed_keys) {
+                log::info!(
+                    watcher = self.watcher.name.as_str(), service = service_name, outcome = "unchanged";
+                    "No changes detected"
+                );
+                return Ok(());
+            }
+
+            if reason == SyncReason::Reconcile && attempt == 1 {
+                log::warn!(
+                    watcher = self.watcher.name.as_str(), service = service_name;
+                    "Drift detected: service env no longer matches Doppler"
+                );
+                self.metrics
+                    .drift_detected(&self.watcher.name, service_name);
+            }
+
+            let plan =
+                crate::docker::plan_env_changes(&docker_secrets, doppler_secrets, &managed_keys)?;
+
+            if self.watcher.dry_run {
+                // A service adopted without any env change still gets its
+                // managed keys label rewritten.
+                let adopt_label = managed_keys.len() != doppler_secrets.len()
+                    || managed_keys
+                        .iter()
+                        .any(|name| !doppler_secrets.contains_key(name));
+
+                log::info!(
+                    watcher = self.watcher.name.as_str(), service = service_name, outcome = "dry_run";
+                    "Dry run: {} changes planned, service not updated",
+                    plan.len() + usize::from(adopt_label)
+                );
+
+                if adopt_label {
+                    log::info!(
+                        watcher = self.watcher.name.as_str(), service = service_name;
+                        "Dry run: would set label {} to the {} Doppler keys",
+                        crate::docker::MANAGED_KEYS_LABEL,
+                        doppler_secrets.len()
+                    );
+                }
+
+                for change in plan {
+                    log::info!(
+                        watcher = self.watcher.name.as_str(), service = service_name;
+                        "Dry run: would {}",
+                        change
+                    );
+                }
+
+                return Ok(());
+            }
+
+            log::info!(
+                watcher = self.watcher.name.as_str(), service = service_name;
+                "Updating service..."
+            );
+
+            let started = Instant::now();
+            let outcome = crate::docker::update_service(
+                &self.engine,
+                &service,
+                docker_secrets,
+                doppler_secrets.clone(),
+            )
+            .await;
+            self.metrics
+                .docker_updated(&self.watcher.name, service_name, started.elapsed());
+            let outcome = outcome?;
+
+            if outcome == UpdateOutcome::Updated {
+                let rollout = crate::docker::wait_for_rollout(
+                    &self.engine,
+                    &service,
+                    service_name,
+                    self.watcher.rollout_timeout(),
+                    &mut self.stop,
+                )
+                .await;
+                self.wanna_stop |= *self.stop.borrow();
+
+                let rollout_outcome = match &rollout {
+                    Ok(Rollout::Converged) => "converged",
+                    Ok(_) => "in_progress",
+                    Err(_) => "failed",
+                };
+                self.audit_update(service_name, reason, &plan, rollout_outcome)
+                    .await;
+
+                match rollout {
+                    Ok(Rollout::Converged) => {
+                        self.metrics
+                            .service_updated(&self.watcher.name, service_name, &plan);
+                        self.applied.services.push(service_name.to_owned());
+                        self.applied
+                            .keys
+                            .extend(plan.iter().map(|change| change.name().to_owned()));
+                    }
+                    // Shutting down before the update finished.
+                    Ok(_) => return Ok(()),
+                    Err(e) => {
+                        self.failed_rollouts
+                            .insert(service_name.to_owned(), doppler_secrets.clone());
+                        self.report_failed_rollouts();
+                        return Err(e);
+                    }
+                }
+            }
+
+            match outcome {
+                UpdateOutcome::Updated => {
+                    log::info!(
+                        watcher = self.watcher.name.as_str(), service = service_name, outcome = "updated";
+                        "Service updated"
+                    );
+                    return Ok(());
+                }
+                UpdateOutcome::Unchanged => {
+                    log::info!(
+                        watcher = self.watcher.name.as_str(), service = service_name, outcome = "unchanged";
+                        "Service already up to date"
+                    );
+                    return Ok(());
+                }
+                UpdateOutcome::VersionConflict => {
+                    log::warn!(
+                        watcher = self.watcher.name.as_str(), service = service_name;
+                        "Service changed during update (attempt {}/{}), retrying",
+                        attempt,
+                        MAX_UPDATE_ATTEMPTS
+                    );
+
+                    if attempt < MAX_UPDATE_ATTEMPTS {
+                        tokio::time::sleep(VERSION_CONFLICT_DELAY.mul_f64(0.5 + fastrand::f64()))
+                            .await;
+                    }
+                }
+            }
+        }
+
+        Err(Error::docker_update(format!(
+            "Failed to update docker service: version conflict after {} attempts",
+            MAX_UPDATE_ATTEMPTS
+        )))
+    }
+
+    /// Posts what the sync changed to the watcher's webhooks in the
+    /// background. Syncs that changed nothing are not announced; failures
+    /// always are.
+    fn notify(&mut self, reason: SyncReason, result: &crate::result::Result<()>) {
+        let applied = std::mem::take(&mut self.applied);
+
+        if self.notifier.is_empty() || (result.is_ok() && applied.services.is_empty()) {
+            return;
+        }
+
+        let notification = Notification {
+            watcher: self.watcher.name.clone(),
+            reason: reason.to_string(),
+            outcome: if result.is_ok() {
+                Outcome::Ok
+            } else {
+                Outcome::Failed
+            },
+            services: applied.services,
+            changed_keys: applied.keys.into_iter().collect(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+            sync_id: crate::logging::sync_id(),
+        };
+
+        let notifier = self.notifier.clone();
+        tokio::spawn(async move {
+            // Failures are logged by the notifier.
+            let _ = notifier.send(&notification).await;
+        });
+    }
+
+    /// Appends the applied changes to the audit log. The update already
+    /// happened, so a write failure is logged rather than failing the sync.
+    async fn audit_update(
+        &self,
+        service_name: &str,
+        reason: SyncReason,
+        plan: &[PlannedChange],
+        rollout: &str,
+    ) {
+        // A service adopted without env changes only gets its label.
+        let Some(auditor) = self.auditor.clone().filter(|_| !plan.is_empty()) else {
+            return;
+        };
+
+        let mut entry = auditor.entry(
+            &self.watcher.name,
+            service_name,
+            reason,
+            plan,
+            std::time::SystemTime::now(),
+        );
+        entry.rollout = Some(rollout.to_owned());
+
+        let result = tokio::task::spawn_blocking(move || auditor.append(&entry))
+            .await
+            .unwrap_or_else(|e| Err(Error::internal("Audit log writer crashed").with_source(e)));
+
+        if let Err(e) = result {
+            log::error!(
+                watcher = self.watcher.name.as_str(), service = service_name;
+                "Failed to write audit entry: {e}"
+            );
+        }
+    }
+
+    /// Schedules a sync once no new `secrets.update` event arrived for a
+    /// debounce window. The sync fetches the secrets then, so it applies the
+    /// latest edit of the burst.
+    fn queue_secrets_update(&mut self, sync_id: String) {
+        let now = Instant::now();
+        let pending = self.pending_update.get_or_insert(PendingUpdate {
+            first: now,
+            due: now,
+            events: 0,
+            sync_id,
+        });
+
+        pending.events += 1;
+        pending.due =
+            (now + self.debounce).min(pending.first + self.debounce * MAX_DEBOUNCE_WINDOWS);
+    }
+
+    async fn sync_pending_update(&mut self) {
+        let Some(pending) = self.pending_update.take() else {
+            return;
+        };
+
+        crate::logging::in_sync(pending.sync_id.clone(), async {
+            if pending.events > 1 {
+                log::info!(
+                    watcher = self.watcher.name.as_str();
+                    "Coalesced {} secrets.update events into one sync",
+                    pending.events
+                );
+            }
+
+            // `sync_in_scope` logs the failure itself.
+            match self.sync_in_scope(SyncReason::SecretsUpdate).await {
+                Ok(()) => self.schedule_reconcile(),
+                Err(e) if e.is_retryable() => {
+                    let delay = e.retry_after().unwrap_or(SYNC_RETRY_DELAY);
+                    log::info!(
+                        watcher = self.watcher.name.as_str();
+                        "Retrying secrets sync in {:.1}s",
+                        delay.as_secs_f64()
+                    );
+                    self.retry_secrets_update(delay, pending.sync_id.clone());
+                }
+                Err(_) => {}
+            }
+        })
+        .await;
+    }
+
+    /// Queues the failed sync again, under the same sync id. New
+    /// `secrets.update` events can still bring it forward.
+    fn retry_secrets_update(&mut self, delay: Duration, sync_id: String) {
+        let now = Instant::now();
+        self.pending_update = Some(PendingUpdate {
+            first: now,
+            due: now + delay,
+            events: 0,
+            sync_id,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{
+        temp_auditor, wait_until, FakeDocker, FakeDoppler, FakeResponse, FakeWebhook,
+    };
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+
+    fn service_json(version: u64, env: &str) -> String {
+        format!(
+            r#"{{"ID": "abc123", "Version": {{"Index": {version}}},
+                "Spec": {{"Name": "backend", "TaskTemplate": {{"ContainerSpec": {{"Env": [{env}]}}}}}}}}"#
+        )
+    }
+
+    fn reconciler(engine: Engine) -> Reconciler {
+        let watcher = config::Watcher {
+            name: "My watcher".to_owned(),
+            docker_services: vec!["backend".to_owned()],
+            doppler_token: "secret".to_owned(),
+            ..Default::default()
+        };
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let (_syncs, requests) = mpsc::channel(1);
+
+        Reconciler::new(watcher, engine, rx, requests)
+    }
+
+    fn doppler_secrets() -> EnvVars {
+        let mut secrets = EnvVars::new();
+        secrets.insert("VAR1".to_owned(), "new_value1".into());
+        secrets
+    }
+
+    #[tokio::test]
+    async fn test_sync_service_retries_on_version_conflict() {
+        // Someone else bumps the version once between our inspect and update.
+        let version = Arc::new(AtomicU64::new(10));
+        let docker = FakeDocker::start({
+            let version = version.clone();
+            move |request| {
+                let current = version.load(Ordering::SeqCst);
+                if request.path.starts_with("/tasks") {
+                    return FakeResponse::json(200, "[]");
+                }
+                if request.method == "GET" {
+                    return FakeResponse::json(200, service_json(current, r#""VAR1=old""#));
+                }
+                if request.path.ends_with(&format!("version={current}")) && current == 11 {
+                    FakeResponse::json(200, "{}")
+                } else {
+                    version.store(11, Ordering::SeqCst);
+                    FakeResponse::json(500, r#"{"message": "update out of sequence"}"#)
+                }
+            }
+        });
+
+        reconciler(docker.engine())
+            .sync_service("backend", &doppler_secrets(), SyncReason::SecretsUpdate)
+            .await
+            .unwrap();
+
+        let updates: Vec<_> = docker
+            .requests()
+            .into_iter()
+            .filter(|r| r.method == "POST")
+            .map(|r| r.path)
+            .collect();
+        assert_eq!(
+            updates,
+            vec![
+                "/services/abc123/update?version=10".to_owned(),
+                "/services/abc123/update?version=11".to_owned(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_service_gives_up_after_max_attempts() {
+        let docker = FakeDocker::start(|request| {
+            if request.method == "GET" {
+                FakeResponse::json(200, service_json(1, r#""VAR1=old""#))
+            } else {
+                FakeResponse::json(500, r#"{"message": "update out of sequence"}"#)
+            }
+        });
+
+        let result = reconciler(docker.engine())
+            .sync_service("backend", &doppler_secrets(), SyncReason::SecretsUpdate)
+            .await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Failed to update docker service: version conflict after 3 attempts"
+        );
+
+        let updates = docker
+            .requests()
+            .into_iter()
+            .filter(|r| r.method == "POST")
+            .count();
+        assert_eq!(updates, MAX_UPDATE_ATTEMPTS as usize);
+    }
+
+    #[test]
+    fn test_should_update_docker_service() {
+        let doppler = doppler_secrets();
+        let managed = HashSet::from(["VAR1".to_owned()]);
+
+        let mut docker = doppler.clone();
+        assert!(!should_update_docker_service(&doppler, &docker, &managed));
+
+        // Foreign keys do not require an update.
+        docker.insert("SERVICE_ROLE".to_owned(), "web".into());
+        assert!(!should_update_docker_service(&doppler, &docker, &managed));
+
+        // Not adopted yet: the managed keys label has to be written.
+        assert!(should_update_docker_service(
+            &doppler,
+            &docker,
+            &HashSet::new()
+        ));
+
+        docker.insert("VAR1".to_owned(), "stale".into());
+        assert!(should_update_docker_service(&doppler, &docker, &managed));
+    }
+
+    #[tokio::test]
+    async fn test_sync_service_skips_up_to_date_service() {
+        let docker = FakeDocker::start(|_| {
+            FakeResponse::json(
+                200,
+                r#"{"ID": "abc123", "Version": {"Index": 1},
+                    "Spec": {"Name": "backend",
+                             "Labels": {"doppler-swarm.managed-keys": "VAR1"},
+                             "TaskTemplate": {"ContainerSpec": {"Env": ["VAR1=new_value1", "ROLE=web"]}}}}"#,
+            )
+        });
+
+        reconciler(docker.engine())
+            .sync_service("backend", &doppler_secrets(), SyncReason::SecretsUpdate)
+            .await
+            .unwrap();
+
+        assert!(docker.requests().iter().all(|r| r.method == "GET"));
+    }
+
+    #[tokio::test]
+    async fn test_sync_service_dry_run_does_not_update() {
+        // The second service only needs its managed keys label adopted.
+        for env in [r#""VAR1=old_value1""#, r#""VAR1=new_value1""#] {
+            let docker = FakeDocker::start(move |_| FakeResponse::json(200, service_json(1, env)));
+
+            let mut reconciler = reconciler(docker.engine());
+            reconciler.watcher.dry_run = true;
+
+            reconciler
+                .sync_service("backend", &doppler_secrets(), SyncReason::SecretsUpdate)
+                .await
+                .unwrap();
+
+            assert!(docker.requests().iter().all(|r| r.method == "GET"));
+        }
+    }
+
+    struct Harness {
+        reconciler: Reconciler,
+        stop: tokio::sync::watch::Sender<bool>,
+        syncs: mpsc::Sender<SyncRequest>,
+    }
+
+    fn harness(doppler: &FakeDoppler, docker: &FakeDocker) -> Harness {
+        let watcher = config::Watcher {
+            name: "My watcher".to_owned(),
+            docker_services: vec!["backend".to_owned()],
+            doppler_token: "secret".to_owned(),
+            doppler_api_url: Some(doppler.url()),
+            debounce_seconds: Some(0),
+            ..Default::default()
+        };
+        let (stop, rx) = tokio::sync::watch::channel(false);
+        let (syncs, requests) = mpsc::channel(16);
+
+        Harness {
+            reconciler: Reconciler::new(watcher, docker.engine(), rx, requests),
+            stop,
+            syncs,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_reconciles_drift_periodically() {
+        let doppler = FakeDoppler::start(&[("API_KEY", "v1")]).await;
+        let docker = FakeDocker::swarm(&[("backend", &["API_KEY=v1"])]);
+        let Harness {
+            mut reconciler,
+            stop,
+            syncs: _syncs,
+        } = harness(&doppler, &docker);
+        reconciler.watcher.reconcile_interval_seconds = Some(3600);
+        reconciler.next_reconcile = Some(Instant::now() + Duration::from_millis(50));
+
+        let handle = tokio::spawn(async move {
+            reconciler.run().await;
+            reconciler
+        });
+
+        // The first reconciliation only adopts the service.
+        wait_until("first reconciliation", || doppler.downloads() == 1).await;
+        wait_until("managed keys label", || docker.updates().len() == 1).await;
+        assert_eq!(docker.env("backend"), vec!["API_KEY=v1"]);
+
+        stop.send(true).unwrap();
+        let reconciler = handle.await.unwrap();
+
+        // The next one is a full interval away.
+        let next = reconciler.next_reconcile.unwrap();
+        assert!(next > Instant::now() + Duration::from_secs(3500));
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_restores_drifted_env() {
+        let doppler = FakeDoppler::start(&[("API_KEY", "v1")]).await;
+        let docker = FakeDocker::swarm(&[("backend", &["API_KEY=v1"])]);
+        let mut reconciler = harness(&doppler, &docker).reconciler;
+        reconciler.watcher.reconcile_interval_seconds = Some(60);
+
+        reconciler.sync_secrets(SyncReason::Startup).await.unwrap();
+        assert_eq!(docker.updates().len(), 1);
+
+        // Someone edits the service by hand.
+        docker.set_env("backend", &["API_KEY=manual"]);
+
+        reconciler.reconcile().await;
+        assert_eq!(docker.updates().len(), 2);
+        assert_eq!(docker.env("backend"), vec!["API_KEY=v1"]);
+        assert_eq!(doppler.downloads(), 2);
+
+        let metrics = reconciler.metrics.render();
+        assert!(metrics.contains(
+            r#"doppler_swarm_drift_detected_total{service="backend",watcher="My watcher"} 1"#
+        ));
+        assert!(metrics.contains(
+            r#"doppler_swarm_env_changes_total{change="changed",service="backend",watcher="My watcher"} 1"#
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_startup_sync_waits_out_rate_limit() {
+        let doppler = FakeDoppler::start(&[("API_KEY", "v1")]).await;
+        doppler.queue_download_response(FakeResponse::json(429, "{}").header("Retry-After", "0"));
+        let docker = FakeDocker::swarm(&[("backend", &[])]);
+        let mut harness = harness(&doppler, &docker);
+
+        harness.reconciler.sync_at_startup().await.unwrap();
+
+        assert_eq!(doppler.downloads(), 2);
+        assert_eq!(docker.env("backend"), vec!["API_KEY=v1"]);
+    }
+
+    #[tokio::test]
+    async fn test_applied_updates_are_audited() {
+        let doppler = FakeDoppler::start(&[("API_KEY", "v1")]).await;
+        let docker = FakeDocker::swarm(&[("backend", &["API_KEY=v1"])]);
+        let mut reconciler = harness(&doppler, &docker).reconciler;
+        let auditor = temp_auditor(None, None);
+        reconciler.record_audit(auditor.clone());
+
+        reconciler.sync_secrets(SyncReason::Startup).await.unwrap();
+        docker.set_env("backend", &["API_KEY=manual"]);
+        reconciler.reconcile().await;
+
+        // Adopting the service at startup changed no env, so only the
+        // reconcile is recorded.
+        let entries = auditor.query(&Default::default()).unwrap();
+        assert_eq!(entries.len(), 1);
+
+        let entry = &entries[0];
+        assert_eq!(entry.watcher, "My watcher");
+        assert_eq!(entry.service, "backend");
+        assert_eq!(entry.trigger, "reconcile");
+        assert!(entry.sync_id.is_some());
+        assert_eq!(entry.rollout.as_deref(), Some("converged"));
+        let expected = auditor.entry(
+            "My watcher",
+            "backend",
+            SyncReason::Reconcile,
+            &[PlannedChange::Change {
+                name: "API_KEY".to_owned(),
+                old_value: "manual".into(),
+                new_value: "v1".into(),
+            }],
+            std::time::SystemTime::now(),
+        );
+        assert_eq!(entry.changed, expected.changed);
+    }
+
+    fn service_event(action: &str, service_name: &str) -> Result<ServiceEvent, RecvError> {
+        Ok(serde_json::from_value(serde_json::json!({
+            "Action": action,
+            "Actor": {"ID": format!("id-{service_name}"), "Attributes": {"name": service_name}},
+            "time": 1700000000,
+        }))
+        .unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_overwritten_service_is_reapplied_without_looping() {
+        let doppler = FakeDoppler::start(&[("API_KEY", "v1")]).await;
+        let docker = FakeDocker::swarm(&[("backend", &["API_KEY=v1"])]);
+        let mut reconciler = harness(&doppler, &docker).reconciler;
+
+        // Nothing to compare against before the first sync.
+        reconciler
+            .handle_service_event(service_event("update", "backend"))
+            .await;
+        assert!(docker.updates().is_empty());
+
+        reconciler.sync_secrets(SyncReason::Startup).await.unwrap();
+        assert_eq!(docker.updates().len(), 1);
+
+        // A stack deploy resets the env.
+        docker.set_env("backend", &["API_KEY=from-stack-file"]);
+        reconciler
+            .handle_service_event(service_event("update", "backend"))
+            .await;
+        assert_eq!(docker.updates().len(), 2);
+        assert_eq!(docker.env("backend"), vec!["API_KEY=v1"]);
+        assert_eq!(doppler.downloads(), 1);
+
+        // The event for our own update is a no-op.
+        reconciler
+            .handle_service_event(service_event("update", "backend"))
+            .await;
+        assert_eq!(docker.updates().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_reapply_gives_up_when_service_keeps_being_overwritten() {
+        let doppler = FakeDoppler::start(&[("API_KEY", "v1")]).await;
+        let docker = FakeDocker::swarm(&[("backend", &["API_KEY=v1"])]);
+        let mut reconciler = harness(&doppler, &docker).reconciler;
+        reconciler.sync_secrets(SyncReason::Startup).await.unwrap();
+
+        for _ in 0..MAX_REAPPLIES + 2 {
+            docker.set_env("backend", &["API_KEY=from-stack-file"]);
+            reconciler
+                .handle_service_event(service_event("update", "backend"))
+                .await;
+        }
+
+        assert_eq!(docker.updates().len(), 1 + MAX_REAPPLIES);
+        assert_eq!(docker.env("backend"), vec!["API_KEY=from-stack-file"]);
+    }
+
+    #[tokio::test]
+    async fn test_reapply_history_forgets_quiet_services() {
+        let docker = FakeDocker::swarm(&[]);
+        let mut reconciler = reconciler(docker.engine());
+        let now = Instant::now();
+        assert!(reconciler.allow_reapply_at("removed", now));
+
+        assert!(reconciler.allow_reapply_at("backend", now + REAPPLY_WINDOW));
+
+        assert_eq!(reconciler.reapplied.keys().collect::<Vec<_>>(), ["backend"]);
+    }
+
+    #[tokio::test]
+    async fn test_burst_of_sync_requests_is_coalesced() {
+        let doppler = FakeDoppler::start(&[("API_KEY", "v0")]).await;
+        let docker = FakeDocker::swarm(&[("backend", &["API_KEY=v0"])]);
+        let Harness {
+            mut reconciler,
+            stop,
+            syncs,
+        } = harness(&doppler, &docker);
+        reconciler.debounce = Duration::from_millis(200);
+
+        let handle = tokio::spawn(async move { reconciler.run().await });
+
+        for version in 1..=5 {
+            doppler.set_secret("API_KEY", &format!("v{version}"));
+            syncs
+                .send(SyncRequest::SecretsUpdate {
+                    sync_id: format!("{version:016x}"),
+                })
+                .await
+                .unwrap();
+        }
+
+        wait_until("sync", || docker.updates().len() == 1).await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(docker.updates().len(), 1);
+        assert_eq!(doppler.downloads(), 1);
+        assert_eq!(docker.env("backend"), vec!["API_KEY=v5"]);
+
+        stop.send(true).unwrap();
+        handle.await.unwrap();
+    }
+
+    #[test]
+    fn test_debounce_is_capped_during_a_steady_trickle() {
+        let mut reconciler = reconciler(Engine::new("/nonexistent.sock"));
+        reconciler.debounce = Duration::from_secs(10);
+
+        reconciler.queue_secrets_update("0000000000000001".to_owned());
+        let first = reconciler.pending_update.clone().unwrap();
+        assert_eq!(first.due, first.first + Duration::from_secs(10));
+
+        // Pretend the burst started long ago.
+        reconciler.pending_update.as_mut().unwrap().first -= Duration::from_secs(35);
+        reconciler.queue_secrets_update("0000000000000002".to_owned());
+
+        let pending = reconciler.pending_update.unwrap();
+        assert_eq!(pending.events, 2);
+        assert_eq!(pending.sync_id, "0000000000000001");
+        assert_eq!(pending.due, pending.first + Duration::from_secs(40));
+    }
+
+    #[tokio::test]
+    async fn test_failed_sync_is_queued_again() {
+        let doppler = FakeDoppler::start(&[("API_KEY", "v1")]).await;
+        doppler.queue_download_response(FakeResponse::json(503, ""));
+        let docker = FakeDocker::swarm(&[("backend", &["API_KEY=v0"])]);
+        let mut reconciler = harness(&doppler, &docker).reconciler;
+
+        reconciler.queue_secrets_update("00000000000000aa".to_owned());
+        reconciler.sync_pending_update().await;
+        assert!(docker.updates().is_empty());
+
+        let retry = reconciler
+            .pending_update
+            .as_ref()
+            .expect("Sync was not queued again");
+        assert!(retry.due > Instant::now() + Duration::from_secs(20));
+        assert_eq!(retry.sync_id, "00000000000000aa");
+
+        reconciler.pending_update.as_mut().unwrap().due = Instant::now();
+        reconciler.sync_pending_update().await;
+        assert_eq!(docker.env("backend"), vec!["API_KEY=v1"]);
+        assert!(reconciler.pending_update.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rolled_back_service_is_left_alone_until_doppler_changes() {
+        let doppler = FakeDoppler::start(&[("API_KEY", "v1")]).await;
+        let docker = FakeDocker::swarm(&[("backend", &["API_KEY=v0"])]);
+        docker.roll_back_updates("backend");
+        let mut reconciler = harness(&doppler, &docker).reconciler;
+        let auditor = temp_auditor(None, None);
+        reconciler.record_audit(auditor.clone());
+
+        let error = reconciler
+            .sync_secrets(SyncReason::SecretsUpdate)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::DockerRollout { .. }));
+        assert_eq!(docker.updates().len(), 1);
+        assert_eq!(docker.env("backend"), vec!["API_KEY=v0"]);
+
+        let entries = auditor.query(&Default::default()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].rollout.as_deref(), Some("failed"));
+        let metrics = reconciler.metrics.render();
+        assert!(!metrics.contains("doppler_swarm_services_updated_total{"));
+        // The update itself still took time.
+        assert!(metrics.contains(
+            r#"doppler_swarm_docker_update_duration_seconds_count{service="backend",watcher="My watcher"} 1"#
+        ));
+
+        // The rollback's update event and later reconciliations don't
+        // apply the same secrets again.
+        reconciler
+            .handle_service_event(service_event("update", "backend"))
+            .await;
+        reconciler.reconcile().await;
+        assert_eq!(docker.updates().len(), 1);
+
+        // Skipping it still fails the sync.
+        let status = &reconciler.status.report().watchers["My watcher"];
+        assert_eq!(status.failed_rollouts, vec!["backend"]);
+        let last_sync = status.last_sync.as_ref().unwrap();
+        assert!(!last_sync.ok);
+        assert_eq!(
+            last_sync.error.as_deref(),
+            Some("Rolling update failed for backend, waiting for a change in Doppler")
+        );
+
+        // New secrets are tried again.
+        doppler.set_secret("API_KEY", "v2");
+        assert!(reconciler
+            .sync_secrets(SyncReason::SecretsUpdate)
+            .await
+            .is_err());
+        assert_eq!(docker.updates().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_changes_and_failures_are_notified() {
+        let doppler = FakeDoppler::start(&[("API_KEY", "v1")]).await;
+        let docker = FakeDocker::swarm(&[("backend", &["API_KEY=v0"])]);
+        let webhook = FakeWebhook::start().await;
+        let mut reconciler = harness(&doppler, &docker).reconciler;
+        reconciler.notifier = Notifier::new(vec![config::Webhook {
+            url: webhook.url(),
+            format: config::WebhookFormat::Generic,
+        }]);
+
+        reconciler
+            .sync_secrets(SyncReason::SecretsUpdate)
+            .await
+            .unwrap();
+        wait_until("change notification", || webhook.bodies().len() == 1).await;
+
+        let notification = &webhook.bodies()[0];
+        assert_eq!(notification["outcome"], "ok");
+        assert_eq!(notification["reason"], "secrets update");
+        assert_eq!(notification["services"], serde_json::json!(["backend"]));
+        assert_eq!(notification["changed_keys"], serde_json::json!(["API_KEY"]));
+
+        // Nothing to change: no notification.
+        reconciler.reconcile().await;
+
+        doppler.queue_download_response(FakeResponse::json(503, ""));
+        assert!(reconciler
+            .sync_secrets(SyncReason::SecretsUpdate)
+            .await
+            .is_err());
+        wait_until("failure notification", || webhook.bodies().len() == 2).await;
+
+        let notification = &webhook.bodies()[1];
+        assert_eq!(notification["outcome"], "failed");
+        assert_eq!(notification["services"], serde_json::json!([]));
+        assert!(notification["error"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_run_stops_when_watch_stream_is_gone() {
+        let doppler = FakeDoppler::start(&[]).await;
+        let docker = FakeDocker::swarm(&[]);
+        let Harness {
+            mut reconciler,
+            stop: _stop,
+            syncs,
+        } = harness(&doppler, &docker);
+
+        drop(syncs);
+
+        tokio::time::timeout(Duration::from_secs(5), reconciler.run())
+            .await
+            .expect("Reconciler kept running");
+    }
+}
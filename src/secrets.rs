@@ -1,28 +1,430 @@
 use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
 
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+
+pub const DEFAULT_DOPPLER_API_URL: &str = "https://api.doppler.com";
+
+/// Used when a 429 response says neither `Retry-After` nor `x-ratelimit-reset`.
+const DEFAULT_RATE_LIMIT_DELAY: Duration = Duration::from_secs(60);
+/// Upper bound on how long we honour a rate limit delay for.
+const MAX_RATE_LIMIT_DELAY: Duration = Duration::from_secs(300);
+
+/// Env vars keyed by name, with values that never print in plaintext.
+pub type EnvVars = HashMap<String, SecretValue>;
+
+/// A secret value. `Debug` and `Display` only show a short fingerprint so
+/// secrets cannot leak into logs by accident; use `expose` to get the
+/// plaintext where it is really needed.
+#[derive(Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(transparent)]
+pub struct SecretValue(String);
+
+impl SecretValue {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// First 8 hex digits of the SHA-256 of the value.
+    pub fn fingerprint(&self) -> String {
+        let digest = Sha256::digest(self.0.as_bytes());
+        digest[..4].iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    /// HMAC-SHA256 of the value under `key`, in hex. Unlike the fingerprint,
+    /// it can't be matched against a list of guessed values without the key.
+    pub fn keyed_hash(&self, key: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+        mac.update(self.0.as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+}
+
+impl std::fmt::Display for SecretValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<redacted sha256:{}>", self.fingerprint())
+    }
+}
+
+impl std::fmt::Debug for SecretValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+impl From<String> for SecretValue {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl From<&str> for SecretValue {
+    fn from(value: &str) -> Self {
+        Self(value.to_owned())
+    }
+}
+
+/// A Doppler API request answered with an error status.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DopplerError {
+    /// 401: the service token is invalid or was revoked.
+    InvalidToken(String),
+    /// 402/403 from the watch endpoint: live updates need a Team or
+    /// Enterprise plan.
+    WatchNotSupported(String),
+    /// 5xx: Doppler is having trouble, worth retrying.
+    Server {
+        status: reqwest::StatusCode,
+        message: String,
+    },
+    /// Any other unexpected status.
+    Status {
+        status: reqwest::StatusCode,
+        message: String,
+    },
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ErrorResponse {
+    #[serde(default)]
+    messages: Vec<String>,
+}
+
+impl DopplerError {
+    pub fn from_response(status: reqwest::StatusCode, body: &[u8]) -> Self {
+        let message = error_message(status, body);
+
+        match status {
+            reqwest::StatusCode::UNAUTHORIZED => Self::InvalidToken(message),
+            status if status.is_server_error() => Self::Server { status, message },
+            status => Self::Status { status, message },
+        }
+    }
+
+    /// Like `from_response`, but for the watch endpoint, where a 402 or 403
+    /// means the workplace plan does not include live updates.
+    pub fn from_watch_response(status: reqwest::StatusCode, body: &[u8]) -> Self {
+        match status {
+            reqwest::StatusCode::PAYMENT_REQUIRED | reqwest::StatusCode::FORBIDDEN => {
+                Self::WatchNotSupported(error_message(status, body))
+            }
+            _ => Self::from_response(status, body),
+        }
+    }
+
+    /// Whether retrying the same request can't succeed without a config change.
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, Self::InvalidToken(_) | Self::WatchNotSupported(_))
+    }
+}
+
+impl std::fmt::Display for DopplerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidToken(message) => write!(f, "INVALID DOPPLER TOKEN: {message}"),
+            Self::WatchNotSupported(message) => write!(
+                f,
+                "Doppler plan does not support watching secrets (Team or Enterprise plan required): {message}"
+            ),
+            Self::Server { status, message } => {
+                write!(f, "Doppler server error {status}: {message}")
+            }
+            Self::Status { status, message } => {
+                write!(f, "Doppler API returned {status}: {message}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DopplerError {}
+
+/// Doppler reports errors as `{"messages": [...], "success": false}`. Other
+/// bodies (e.g. a proxy's HTML error page) are not worth echoing.
+fn error_message(status: reqwest::StatusCode, body: &[u8]) -> String {
+    match serde_json::from_slice::<ErrorResponse>(body) {
+        Ok(error) if !error.messages.is_empty() => error.messages.join("; "),
+        _ => status
+            .canonical_reason()
+            .unwrap_or("unknown error")
+            .to_owned(),
+    }
+}
+
+/// How long Doppler asked us to back off for after a 429: `Retry-After`
+/// (seconds or an HTTP date), else `x-ratelimit-reset` (unix seconds), else
+/// a default. Capped at `MAX_RATE_LIMIT_DELAY`.
+pub fn rate_limit_delay(headers: &reqwest::header::HeaderMap, now: SystemTime) -> Duration {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    let retry_after = header("retry-after").and_then(|value| {
+        let value = value.trim();
+        match value.parse::<u64>() {
+            Ok(secs) => Some(Duration::from_secs(secs)),
+            Err(_) => httpdate::parse_http_date(value)
+                .ok()
+                .map(|date| date.duration_since(now).unwrap_or_default()),
+        }
+    });
+
+    let reset = || {
+        header("x-ratelimit-reset")
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .map(|secs| {
+                (SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+                    .duration_since(now)
+                    .unwrap_or_default()
+            })
+    };
+
+    retry_after
+        .or_else(reset)
+        .unwrap_or(DEFAULT_RATE_LIMIT_DELAY)
+        .min(MAX_RATE_LIMIT_DELAY)
+}
+
+fn warn_if_rate_limit_exhausted(headers: &reqwest::header::HeaderMap) {
+    let remaining = headers
+        .get("x-ratelimit-remaining")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok());
+
+    if remaining == Some(0) {
+        log::warn!(
+            "Doppler rate limit exhausted, resets in {}s",
+            rate_limit_delay(headers, SystemTime::now()).as_secs()
+        );
+    }
+}
+
+/// Downloads the secrets of a config. A rate limited download fails right
+/// away with `Error::DopplerRateLimited`; the caller decides how to wait.
 pub async fn fetch_secrets(
     http: &reqwest::Client,
+    api_url: &str,
     doppler_token: &str,
-) -> crate::result::Result<HashMap<String, String>> {
+) -> crate::result::Result<EnvVars> {
     let response = http
-        .get("https://api.doppler.com/v3/configs/config/secrets/download?format=json")
+        .get(format!(
+            "{api_url}/v3/configs/config/secrets/download?format=json"
+        ))
         .bearer_auth(doppler_token)
         .send()
         .await
-        .map_err(|e| format!("{e}"))?;
+        .map_err(|e| Error::doppler_http("Failed to fetch secrets", e))?;
 
-    match response.status() {
-        reqwest::StatusCode::OK => {}
-        reqwest::StatusCode::UNAUTHORIZED => {
-            return Err("INVALID DOPPLER TOKEN".into());
-        }
-        _ => return Err(format!("HTTP Status {}", response.status()).into()),
+    if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        return Err(Error::DopplerRateLimited {
+            message: "Failed to fetch secrets: rate limited by Doppler".to_owned(),
+            retry_after: rate_limit_delay(response.headers(), SystemTime::now()),
+        });
     }
 
-    let secrets: HashMap<String, String> = response
-        .json()
-        .await
-        .map_err(|e| format!("Cannot read response body: {}", e))?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.bytes().await.unwrap_or_default();
+        return Err(Error::doppler(
+            "Failed to fetch secrets",
+            DopplerError::from_response(status, &body),
+        ));
+    }
+
+    warn_if_rate_limit_exhausted(response.headers());
+
+    let secrets: EnvVars = response.json().await.map_err(|e| {
+        Error::doppler_http("Failed to fetch secrets: cannot read response body", e)
+    })?;
 
     Ok(secrets)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakeDoppler, FakeResponse};
+    use reqwest::header::HeaderMap;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+            .collect()
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_rate_limit_delay_from_retry_after_seconds() {
+        let delay = rate_limit_delay(
+            &headers(&[("retry-after", "12"), ("x-ratelimit-reset", "1000")]),
+            at(0),
+        );
+
+        assert_eq!(delay, Duration::from_secs(12));
+    }
+
+    #[test]
+    fn test_rate_limit_delay_from_retry_after_date() {
+        let now = httpdate::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+
+        let delay = rate_limit_delay(
+            &headers(&[("retry-after", "Sun, 06 Nov 1994 08:50:07 GMT")]),
+            now,
+        );
+        assert_eq!(delay, Duration::from_secs(30));
+
+        // A date in the past means we can retry right away.
+        let delay = rate_limit_delay(
+            &headers(&[("retry-after", "Sun, 06 Nov 1994 08:00:00 GMT")]),
+            now,
+        );
+        assert_eq!(delay, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_rate_limit_delay_from_reset_header() {
+        let delay = rate_limit_delay(
+            &headers(&[("retry-after", "soon"), ("x-ratelimit-reset", "1045")]),
+            at(1000),
+        );
+
+        assert_eq!(delay, Duration::from_secs(45));
+    }
+
+    #[test]
+    fn test_rate_limit_delay_default_and_cap() {
+        assert_eq!(
+            rate_limit_delay(&headers(&[]), at(0)),
+            DEFAULT_RATE_LIMIT_DELAY
+        );
+        assert_eq!(
+            rate_limit_delay(&headers(&[("retry-after", "86400")]), at(0)),
+            MAX_RATE_LIMIT_DELAY
+        );
+    }
+
+    #[test]
+    fn test_doppler_error_from_response() {
+        let body = br#"{"messages": ["Invalid Auth token"], "success": false}"#;
+
+        let error = DopplerError::from_response(reqwest::StatusCode::UNAUTHORIZED, body);
+        assert_eq!(
+            error,
+            DopplerError::InvalidToken("Invalid Auth token".to_owned())
+        );
+        assert!(error.is_permanent());
+        assert_eq!(
+            error.to_string(),
+            "INVALID DOPPLER TOKEN: Invalid Auth token"
+        );
+
+        let error = DopplerError::from_response(reqwest::StatusCode::BAD_GATEWAY, b"<html>");
+        assert!(!error.is_permanent());
+        assert_eq!(
+            error.to_string(),
+            "Doppler server error 502 Bad Gateway: Bad Gateway"
+        );
+
+        let error = DopplerError::from_response(
+            reqwest::StatusCode::NOT_FOUND,
+            br#"{"messages": ["Config not found", "Check the token"]}"#,
+        );
+        assert!(!error.is_permanent());
+        assert_eq!(
+            error.to_string(),
+            "Doppler API returned 404 Not Found: Config not found; Check the token"
+        );
+    }
+
+    #[test]
+    fn test_doppler_error_from_watch_response() {
+        let body = br#"{"messages": ["Upgrade to the Team plan"], "success": false}"#;
+
+        let error = DopplerError::from_watch_response(reqwest::StatusCode::FORBIDDEN, body);
+        assert_eq!(
+            error,
+            DopplerError::WatchNotSupported("Upgrade to the Team plan".to_owned())
+        );
+        assert!(error.is_permanent());
+
+        // Outside the watch endpoint a 403 is just an unexpected status.
+        let error = DopplerError::from_response(reqwest::StatusCode::FORBIDDEN, body);
+        assert!(!error.is_permanent());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_secrets_invalid_token() {
+        let doppler = FakeDoppler::start(&[]).await;
+        doppler.queue_download_response(FakeResponse::json(
+            401,
+            r#"{"messages": ["Invalid Auth token"], "success": false}"#,
+        ));
+
+        let result = fetch_secrets(&reqwest::Client::new(), &doppler.url(), "dp.st.test").await;
+
+        let error = result.unwrap_err();
+        assert!(matches!(error, Error::DopplerAuth { .. }));
+        assert_eq!(
+            error.to_string(),
+            "Failed to fetch secrets: INVALID DOPPLER TOKEN: Invalid Auth token"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_secrets_returns_rate_limit_without_waiting() {
+        let doppler = FakeDoppler::start(&[("API_KEY", "v1")]).await;
+        doppler.queue_download_response(FakeResponse::json(429, "{}").header("Retry-After", "120"));
+
+        let result = fetch_secrets(&reqwest::Client::new(), &doppler.url(), "dp.st.test").await;
+
+        let error = result.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to fetch secrets: rate limited by Doppler"
+        );
+        assert_eq!(error.retry_after(), Some(Duration::from_secs(120)));
+        assert_eq!(doppler.downloads(), 1);
+    }
+
+    #[test]
+    fn test_keyed_hash_is_hmac_sha256() {
+        // RFC 4231, test cases 2 and 6.
+        assert_eq!(
+            SecretValue::from("what do ya want for nothing?").keyed_hash(b"Jefe"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            SecretValue::from("Test Using Larger Than Block-Size Key - Hash Key First")
+                .keyed_hash(&[0xaa; 131]),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn test_secret_value_is_redacted() {
+        let value = SecretValue::from("hunter2");
+
+        assert_eq!(value.expose(), "hunter2");
+        assert_eq!(format!("{value}"), "<redacted sha256:f52fbd32>");
+        assert_eq!(format!("{value:?}"), "<redacted sha256:f52fbd32>");
+
+        let mut env_vars = EnvVars::new();
+        env_vars.insert("PASSWORD".to_owned(), value);
+        assert!(!format!("{env_vars:?}").contains("hunter2"));
+    }
+
+    #[test]
+    fn test_secret_value_deserializes_transparently() {
+        let env_vars: EnvVars = serde_json::from_str(r#"{"PASSWORD": "hunter2"}"#).unwrap();
+
+        assert_eq!(env_vars["PASSWORD"].expose(), "hunter2");
+    }
+}
@@ -0,0 +1,169 @@
+use std::convert::Infallible;
+
+use hyper::{service::service_fn, Body, Method, Request, Response, StatusCode};
+use tokio::{net::TcpListener, sync::watch};
+
+use crate::{metrics::Metrics, status::Status};
+
+/// Serves `/healthz`, `/readyz`, `/status` and `/metrics` until `stop` flips
+/// to true.
+pub async fn serve(
+    listener: TcpListener,
+    status: Status,
+    metrics: Metrics,
+    mut stop: watch::Receiver<bool>,
+) {
+    if let Ok(addr) = listener.local_addr() {
+        log::info!("Serving health and status on http://{addr}");
+    }
+
+    while !*stop.borrow() {
+        let stream = tokio::select! {
+            changed = stop.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                continue;
+            }
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    log::warn!("Failed to accept status connection: {e}");
+                    continue;
+                }
+            },
+        };
+
+        let status = status.clone();
+        let metrics = metrics.clone();
+        let service = service_fn(move |request: Request<Body>| {
+            let status = status.clone();
+            let metrics = metrics.clone();
+            async move { Ok::<_, Infallible>(respond(&status, &metrics, &request)) }
+        });
+
+        tokio::spawn(async move {
+            if let Err(e) = hyper::server::conn::Http::new()
+                .serve_connection(stream, service)
+                .await
+            {
+                log::debug!("Status connection closed with error: {e}");
+            }
+        });
+    }
+}
+
+fn respond(status: &Status, metrics: &Metrics, request: &Request<Body>) -> Response<Body> {
+    if request.method() != Method::GET {
+        return text(StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+    }
+
+    match request.uri().path() {
+        "/healthz" => text(StatusCode::OK, "ok"),
+        "/readyz" if status.is_ready() => text(StatusCode::OK, "ready"),
+        "/readyz" => text(StatusCode::SERVICE_UNAVAILABLE, "not ready"),
+        "/status" => match serde_json::to_vec(&status.report()) {
+            Ok(body) => Response::builder()
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap(),
+            Err(e) => text(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+        },
+        "/metrics" => Response::builder()
+            .header(
+                hyper::header::CONTENT_TYPE,
+                "text/plain; version=0.0.4; charset=utf-8",
+            )
+            .body(Body::from(metrics.render()))
+            .unwrap(),
+        _ => text(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+fn text(status: StatusCode, body: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "text/plain")
+        .body(Body::from(body.to_owned()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reconciler::SyncReason;
+
+    async fn start(status: Status, metrics: Metrics) -> (String, watch::Sender<bool>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = watch::channel(false);
+
+        tokio::spawn(serve(listener, status, metrics, rx));
+
+        (url, tx)
+    }
+
+    #[tokio::test]
+    async fn test_health_and_readiness() {
+        let status = Status::default();
+        status.register("production app");
+        let (url, _tx) = start(status.clone(), Metrics::default()).await;
+
+        let health = reqwest::get(format!("{url}/healthz")).await.unwrap();
+        assert_eq!(health.status(), 200);
+
+        let ready = reqwest::get(format!("{url}/readyz")).await.unwrap();
+        assert_eq!(ready.status(), 503);
+
+        status.sync_finished("production app", SyncReason::Startup, &Ok(()));
+        status.stream_connected("production app");
+        status.stream_event("production app");
+
+        let ready = reqwest::get(format!("{url}/readyz")).await.unwrap();
+        assert_eq!(ready.status(), 200);
+
+        let missing = reqwest::get(format!("{url}/nope")).await.unwrap();
+        assert_eq!(missing.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_status_lists_watchers() {
+        let status = Status::default();
+        status.set_services("production app", &["backend".to_owned()]);
+        status.sync_finished("production app", SyncReason::Startup, &Ok(()));
+        let (url, _tx) = start(status, Metrics::default()).await;
+
+        let report: serde_json::Value = reqwest::get(format!("{url}/status"))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+
+        assert_eq!(report["ready"], false);
+        let watcher = &report["watchers"]["production app"];
+        assert_eq!(watcher["services"], serde_json::json!(["backend"]));
+        assert_eq!(watcher["stream_connected"], false);
+        assert_eq!(watcher["last_event_at"], serde_json::Value::Null);
+        assert_eq!(watcher["last_sync"]["ok"], true);
+        assert_eq!(watcher["last_sync"]["reason"], "startup");
+    }
+
+    #[tokio::test]
+    async fn test_metrics_are_exported() {
+        let metrics = Metrics::default();
+        metrics.watch_connected("production app");
+        let (url, _tx) = start(Status::default(), metrics).await;
+
+        let text = reqwest::get(format!("{url}/metrics"))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+
+        assert!(
+            text.contains(r#"doppler_swarm_watch_connections_total{watcher="production app"} 1"#)
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use serde::Serialize;
+
+use crate::{backoff::CircuitState, reconciler::SyncReason};
+
+/// Stream silence after which a watcher no longer counts as ready, used when
+/// the config doesn't set `ready_stream_max_age_seconds`. Doppler pings every
+/// 30 seconds.
+pub const DEFAULT_READY_STREAM_MAX_AGE: Duration = Duration::from_secs(90);
+
+/// What each watcher is up to, shared between the workers, reconcilers and
+/// the HTTP server. A default `Status` is simply never read.
+#[derive(Debug, Clone)]
+pub struct Status {
+    watchers: Arc<Mutex<BTreeMap<String, WatcherStatus>>>,
+    ready_stream_max_age: Duration,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WatcherStatus {
+    /// Services the last full sync matched.
+    pub services: Vec<String>,
+    pub initial_sync_done: bool,
+    pub stream_connected: bool,
+    /// Circuit breaker of the watch stream's reconnects.
+    pub circuit_breaker: CircuitState,
+    /// Unix timestamp in seconds of the last event on the watch stream,
+    /// pings included.
+    pub last_event_at: Option<u64>,
+    pub last_sync: Option<SyncStatus>,
+    /// Services whose rolling update failed, left alone until their secrets
+    /// change in Doppler.
+    pub failed_rollouts: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncStatus {
+    /// Unix timestamp in seconds.
+    pub at: u64,
+    pub reason: String,
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatusReport {
+    pub ready: bool,
+    pub watchers: BTreeMap<String, WatcherStatus>,
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Self::new(DEFAULT_READY_STREAM_MAX_AGE)
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Status {
+    pub fn new(ready_stream_max_age: Duration) -> Self {
+        Self {
+            watchers: Arc::default(),
+            ready_stream_max_age,
+        }
+    }
+
+    /// Adds a watcher that is not ready until its first sync and stream.
+    pub fn register(&self, watcher: &str) {
+        self.update(watcher, |_| {});
+    }
+
+    pub fn set_services(&self, watcher: &str, services: &[String]) {
+        self.update(watcher, |status| status.services = services.to_vec());
+    }
+
+    pub fn sync_finished(
+        &self,
+        watcher: &str,
+        reason: SyncReason,
+        result: &crate::result::Result<()>,
+    ) {
+        self.update(watcher, |status| {
+            status.initial_sync_done |= result.is_ok();
+            status.last_sync = Some(SyncStatus {
+                at: unix_seconds(SystemTime::now()),
+                reason: reason.to_string(),
+                ok: result.is_ok(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+            });
+        });
+    }
+
+    pub fn stream_connected(&self, watcher: &str) {
+        self.update(watcher, |status| status.stream_connected = true);
+    }
+
+    pub fn stream_disconnected(&self, watcher: &str) {
+        self.update(watcher, |status| status.stream_connected = false);
+    }
+
+    pub fn circuit_breaker(&self, watcher: &str, state: CircuitState) {
+        self.update(watcher, |status| status.circuit_breaker = state);
+    }
+
+    pub fn failed_rollouts(&self, watcher: &str, services: Vec<String>) {
+        self.update(watcher, |status| status.failed_rollouts = services);
+    }
+
+    pub fn stream_event(&self, watcher: &str) {
+        self.stream_event_at(watcher, SystemTime::now());
+    }
+
+    fn stream_event_at(&self, watcher: &str, at: SystemTime) {
+        self.update(watcher, |status| {
+            status.last_event_at = Some(unix_seconds(at));
+        });
+    }
+
+    /// Every watcher finished its first sync and heard from Doppler
+    /// recently.
+    pub fn is_ready(&self) -> bool {
+        self.report_at(SystemTime::now()).ready
+    }
+
+    pub fn report(&self) -> StatusReport {
+        self.report_at(SystemTime::now())
+    }
+
+    fn report_at(&self, now: SystemTime) -> StatusReport {
+        let watchers = self.watchers.lock().unwrap().clone();
+        let oldest_event = unix_seconds(now).saturating_sub(self.ready_stream_max_age.as_secs());
+
+        let ready = watchers.values().all(|status| {
+            status.initial_sync_done
+                && status.stream_connected
+                && status
+                    .last_event_at
+                    .is_some_and(|last_event| last_event >= oldest_event)
+        });
+
+        StatusReport { ready, watchers }
+    }
+
+    fn update(&self, watcher: &str, f: impl FnOnce(&mut WatcherStatus)) {
+        let mut watchers = self.watchers.lock().unwrap();
+        f(watchers.entry(watcher.to_owned()).or_default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    #[test]
+    fn test_ready_after_initial_sync_and_recent_event() {
+        let status = Status::new(Duration::from_secs(90));
+        status.register("a");
+        assert!(!status.is_ready());
+
+        status.sync_finished("a", SyncReason::Startup, &Ok(()));
+        status.stream_connected("a");
+        assert!(!status.is_ready());
+
+        status.stream_event("a");
+        assert!(status.is_ready());
+
+        status.stream_disconnected("a");
+        assert!(!status.is_ready());
+    }
+
+    #[test]
+    fn test_not_ready_when_stream_is_silent() {
+        let status = Status::new(Duration::from_secs(90));
+        status.sync_finished("a", SyncReason::Startup, &Ok(()));
+        status.stream_connected("a");
+
+        let now = SystemTime::now();
+        status.stream_event_at("a", now - Duration::from_secs(60));
+        assert!(status.report_at(now).ready);
+        assert!(!status.report_at(now + Duration::from_secs(31)).ready);
+    }
+
+    #[test]
+    fn test_failed_sync_is_reported() {
+        let status = Status::default();
+        status.sync_finished("a", SyncReason::Startup, &Ok(()));
+        status.sync_finished(
+            "a",
+            SyncReason::SecretsUpdate,
+            &Err(Error::docker_update("Failed to update docker service")),
+        );
+
+        let report = status.report();
+        let watcher = &report.watchers["a"];
+        // A later failure doesn't undo the initial sync.
+        assert!(watcher.initial_sync_done);
+
+        let last_sync = watcher.last_sync.as_ref().unwrap();
+        assert!(!last_sync.ok);
+        assert_eq!(last_sync.reason, "secrets update");
+        assert_eq!(
+            last_sync.error.as_deref(),
+            Some("Failed to update docker service")
+        );
+    }
+}
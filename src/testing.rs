@@ -0,0 +1,694 @@
+//! Fake backends used by the test suite.
+
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use bytes::Bytes;
+use hyper::{service::service_fn, Body, Response};
+use serde_json::{json, Value};
+
+use crate::{audit::Auditor, config, engine::Engine};
+
+static NEXT_SOCKET_ID: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub path: String,
+    pub body: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct FakeResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    /// How long the fake daemon waits before answering.
+    pub delay: Duration,
+}
+
+impl FakeResponse {
+    pub fn json(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: body.into(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn delayed(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    fn into_response(self) -> Response<Body> {
+        let mut builder = Response::builder()
+            .status(self.status)
+            .header("Content-Type", "application/json");
+
+        for (name, value) in self.headers {
+            builder = builder.header(name, value);
+        }
+
+        builder.body(Body::from(self.body)).unwrap()
+    }
+}
+
+type Handler = dyn Fn(&RecordedRequest) -> FakeResponse + Send + Sync;
+
+type EventStreams = Arc<Mutex<Vec<tokio::sync::mpsc::UnboundedSender<Bytes>>>>;
+
+/// Fake Docker daemon listening on a Unix socket in the temp directory.
+/// Every request is recorded and answered by the provided handler, except
+/// `/events`, which opens a stream fed by `send_event`.
+pub struct FakeDocker {
+    socket: PathBuf,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+    services: Arc<Mutex<Vec<Value>>>,
+    event_streams: EventStreams,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl FakeDocker {
+    pub fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&RecordedRequest) -> FakeResponse + Send + Sync + 'static,
+    {
+        Self::start_with_events(handler, EventStreams::default())
+    }
+
+    fn start_with_events<F>(handler: F, event_streams: EventStreams) -> Self
+    where
+        F: Fn(&RecordedRequest) -> FakeResponse + Send + Sync + 'static,
+    {
+        let socket = std::env::temp_dir().join(format!(
+            "doppler-swarm-test-{}-{}.sock",
+            std::process::id(),
+            NEXT_SOCKET_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_file(&socket);
+
+        let listener = tokio::net::UnixListener::bind(&socket).expect("Cannot bind fake socket");
+        let handler: Arc<Handler> = Arc::new(handler);
+        let requests: Arc<Mutex<Vec<RecordedRequest>>> = Arc::default();
+
+        let task = {
+            let requests = requests.clone();
+            let event_streams = event_streams.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let handler = handler.clone();
+                    let requests = requests.clone();
+                    let event_streams = event_streams.clone();
+                    let service = service_fn(move |request: hyper::Request<Body>| {
+                        let handler = handler.clone();
+                        let requests = requests.clone();
+                        let event_streams = event_streams.clone();
+                        async move {
+                            let method = request.method().to_string();
+                            let path = request
+                                .uri()
+                                .path_and_query()
+                                .map(|p| p.to_string())
+                                .unwrap_or_default();
+                            let body = hyper::body::to_bytes(request.into_body())
+                                .await
+                                .unwrap_or_default();
+
+                            let recorded = RecordedRequest {
+                                method,
+                                path,
+                                body: String::from_utf8_lossy(&body).into_owned(),
+                            };
+                            requests.lock().unwrap().push(recorded.clone());
+
+                            if recorded.path.starts_with("/events") {
+                                let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+                                event_streams.lock().unwrap().push(sender);
+                                let chunks =
+                                    futures::stream::unfold(receiver, |mut receiver| async {
+                                        let chunk = receiver.recv().await?;
+                                        Some((Ok::<_, Infallible>(chunk), receiver))
+                                    });
+                                return Ok(Response::new(Body::wrap_stream(chunks)));
+                            }
+
+                            let response = handler(&recorded);
+                            tokio::time::sleep(response.delay).await;
+                            Ok::<_, Infallible>(response.into_response())
+                        }
+                    });
+
+                    tokio::spawn(
+                        hyper::server::conn::Http::new().serve_connection(stream, service),
+                    );
+                }
+            })
+        };
+
+        Self {
+            socket,
+            requests,
+            services: Arc::default(),
+            event_streams,
+            task,
+        }
+    }
+
+    /// Stateful fake swarm running the given `(name, env)` services.
+    /// Updates replace the spec and bump the version like the real daemon.
+    pub fn swarm(services: &[(&str, &[&str])]) -> Self {
+        let services: Arc<Mutex<Vec<Value>>> = Arc::new(Mutex::new(
+            services
+                .iter()
+                .enumerate()
+                .map(|(i, (name, env))| {
+                    json!({
+                        "ID": format!("id-{name}"),
+                        "Version": {"Index": i + 1},
+                        "Spec": {
+                            "Name": name,
+                            "TaskTemplate": {"ContainerSpec": {"Image": "app:latest", "Env": env}},
+                        },
+                    })
+                })
+                .collect(),
+        ));
+
+        let event_streams = EventStreams::default();
+
+        let mut docker = Self::start_with_events(
+            {
+                let services = services.clone();
+                let event_streams = event_streams.clone();
+                move |request| {
+                    let mut services = services.lock().unwrap();
+                    let response = swarm_response(&mut services, request);
+
+                    // Like the real daemon, report our own updates as events.
+                    if request.method == "POST" && response.status == 200 {
+                        let id = request.path.split('/').nth(2).unwrap_or_default();
+                        if let Some(service) = services.iter().find(|service| service["ID"] == id) {
+                            emit_event(&event_streams, &service_event("update", service));
+                        }
+                    }
+
+                    response
+                }
+            },
+            event_streams,
+        );
+        docker.services = services;
+        docker
+    }
+
+    /// Adds a service to the fake swarm and reports it on the event stream.
+    pub fn create_service(&self, name: &str, env: &[&str]) {
+        let mut services = self.services.lock().unwrap();
+        let service = json!({
+            "ID": format!("id-{name}"),
+            "Version": {"Index": 1},
+            "Spec": {
+                "Name": name,
+                "TaskTemplate": {"ContainerSpec": {"Image": "app:latest", "Env": env}},
+            },
+        });
+
+        emit_event(&self.event_streams, &service_event("create", &service));
+        services.push(service);
+    }
+
+    pub fn live_event_streams(&self) -> usize {
+        self.event_streams.lock().unwrap().len()
+    }
+
+    pub fn close_event_streams(&self) {
+        self.event_streams.lock().unwrap().clear();
+    }
+
+    pub fn socket_path(&self) -> String {
+        self.socket.display().to_string()
+    }
+
+    /// Service update requests received so far.
+    pub fn updates(&self) -> Vec<RecordedRequest> {
+        self.requests()
+            .into_iter()
+            .filter(|request| request.method == "POST")
+            .collect()
+    }
+
+    /// Current env of a service in the fake swarm.
+    pub fn env(&self, service_name: &str) -> Vec<String> {
+        let services = self.services.lock().unwrap();
+        let service = find_service(&services, service_name).expect("Unknown service");
+
+        serde_json::from_value(service["Spec"]["TaskTemplate"]["ContainerSpec"]["Env"].clone())
+            .unwrap_or_default()
+    }
+
+    /// Replaces a service's env the way someone outside doppler-swarm would,
+    /// e.g. `docker service update --env-add` or `docker stack deploy`.
+    pub fn set_env(&self, service_name: &str, env: &[&str]) {
+        let mut services = self.services.lock().unwrap();
+        let service = services
+            .iter_mut()
+            .find(|service| service["Spec"]["Name"] == service_name)
+            .expect("Unknown service");
+
+        service["Spec"]["TaskTemplate"]["ContainerSpec"]["Env"] = json!(env);
+        let version = service["Version"]["Index"].as_u64().unwrap();
+        service["Version"]["Index"] = (version + 1).into();
+
+        emit_event(&self.event_streams, &service_event("update", service));
+    }
+
+    /// Makes Swarm roll back every later update of a service, as if its new
+    /// tasks kept failing.
+    pub fn roll_back_updates(&self, service_name: &str) {
+        let mut services = self.services.lock().unwrap();
+        let service = services
+            .iter_mut()
+            .find(|service| service["Spec"]["Name"] == service_name)
+            .expect("Unknown service");
+
+        service[ROLL_BACK] = true.into();
+    }
+
+    pub fn engine(&self) -> Engine {
+        Engine::new(&self.socket)
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for FakeDocker {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = std::fs::remove_file(&self.socket);
+    }
+}
+
+fn service_event(action: &str, service: &Value) -> Value {
+    json!({
+        "Type": "service",
+        "Action": action,
+        "Actor": {
+            "ID": service["ID"],
+            "Attributes": {"name": service["Spec"]["Name"]},
+        },
+        "scope": "swarm",
+        "time": 1700000000,
+        "timeNano": 1700000000000000000u64,
+    })
+}
+
+fn emit_event(streams: &EventStreams, event: &Value) {
+    let line = Bytes::from(format!("{event}\n"));
+    streams
+        .lock()
+        .unwrap()
+        .retain(|stream| stream.send(line.clone()).is_ok());
+}
+
+fn find_service<'a>(services: &'a [Value], name_or_id: &str) -> Option<&'a Value> {
+    services
+        .iter()
+        .find(|service| service["ID"] == name_or_id || service["Spec"]["Name"] == name_or_id)
+}
+
+/// Marks a fake service whose updates are rolled back. The real daemon has
+/// no such field.
+const ROLL_BACK: &str = "FakeRollBack";
+
+fn swarm_response(services: &mut [Value], request: &RecordedRequest) -> FakeResponse {
+    let path = request.path.split('?').next().unwrap_or_default();
+
+    match (request.method.as_str(), path) {
+        ("GET", "/services") => FakeResponse::json(200, Value::from(services.to_vec()).to_string()),
+        // One task per service, already running its current spec.
+        ("GET", "/tasks") => {
+            let tasks: Vec<Value> = services
+                .iter()
+                .filter(|service| {
+                    request.path.contains(&format!(
+                        "%22{}%22",
+                        service["ID"].as_str().unwrap_or_default()
+                    ))
+                })
+                .map(|service| {
+                    json!({
+                        "Spec": service["Spec"]["TaskTemplate"],
+                        "Status": {"State": "running"},
+                    })
+                })
+                .collect();
+            FakeResponse::json(200, Value::from(tasks).to_string())
+        }
+        ("GET", path) => match find_service(services, path.trim_start_matches("/services/")) {
+            Some(service) => FakeResponse::json(200, service.to_string()),
+            None => FakeResponse::json(404, r#"{"message": "service not found"}"#),
+        },
+        ("POST", path) => {
+            let id = path
+                .trim_start_matches("/services/")
+                .trim_end_matches("/update");
+            let version: u64 = request
+                .path
+                .split("version=")
+                .nth(1)
+                .and_then(|version| version.parse().ok())
+                .unwrap_or_default();
+
+            let Some(service) = services.iter_mut().find(|service| service["ID"] == id) else {
+                return FakeResponse::json(404, r#"{"message": "service not found"}"#);
+            };
+
+            if service["Version"]["Index"] != version {
+                return FakeResponse::json(500, r#"{"message": "update out of sequence"}"#);
+            }
+
+            let previous = std::mem::replace(
+                &mut service["Spec"],
+                serde_json::from_str(&request.body).unwrap(),
+            );
+            service["Version"]["Index"] = (version + 1).into();
+
+            if service[ROLL_BACK] == true {
+                service["Spec"] = previous;
+                service["Version"]["Index"] = (version + 2).into();
+                service["UpdateStatus"] = json!({
+                    "State": "rollback_completed",
+                    "StartedAt": format!("2024-03-01T12:00:{:02}Z", version % 60),
+                    "Message": "rollback completed",
+                });
+            }
+
+            FakeResponse::json(200, r#"{"Warnings": null}"#)
+        }
+        _ => FakeResponse::json(404, r#"{"message": "page not found"}"#),
+    }
+}
+
+#[derive(Default)]
+struct DopplerState {
+    secrets: Mutex<serde_json::Map<String, Value>>,
+    streams: Mutex<Vec<hyper::body::Sender>>,
+    downloads: AtomicUsize,
+    watch_connections: AtomicUsize,
+    last_event_ids: Mutex<Vec<Option<String>>>,
+    watch_response: Mutex<Option<FakeResponse>>,
+    download_responses: Mutex<std::collections::VecDeque<FakeResponse>>,
+}
+
+/// Fake Doppler API serving the secrets download endpoint and the `/watch`
+/// SSE stream on a local TCP port.
+pub struct FakeDoppler {
+    addr: SocketAddr,
+    state: Arc<DopplerState>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl FakeDoppler {
+    pub async fn start(secrets: &[(&str, &str)]) -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Cannot bind fake doppler");
+        let addr = listener.local_addr().unwrap();
+
+        let state = Arc::new(DopplerState::default());
+        for (name, value) in secrets {
+            state
+                .secrets
+                .lock()
+                .unwrap()
+                .insert(name.to_string(), (*value).into());
+        }
+
+        let task = {
+            let state = state.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let state = state.clone();
+                    let service = service_fn(move |request: hyper::Request<Body>| {
+                        let state = state.clone();
+                        async move { Ok::<_, Infallible>(doppler_response(&state, &request)) }
+                    });
+
+                    tokio::spawn(
+                        hyper::server::conn::Http::new().serve_connection(stream, service),
+                    );
+                }
+            })
+        };
+
+        Self { addr, state, task }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn set_secret(&self, name: &str, value: &str) {
+        self.state
+            .secrets
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), value.into());
+    }
+
+    pub fn downloads(&self) -> usize {
+        self.state.downloads.load(Ordering::SeqCst)
+    }
+
+    pub fn watch_connections(&self) -> usize {
+        self.state.watch_connections.load(Ordering::SeqCst)
+    }
+
+    /// `Last-Event-ID` header of every watch request, in order.
+    pub fn last_event_ids(&self) -> Vec<Option<String>> {
+        self.state.last_event_ids.lock().unwrap().clone()
+    }
+
+    pub fn live_streams(&self) -> usize {
+        self.state.streams.lock().unwrap().len()
+    }
+
+    /// Writes raw bytes to every open watch stream.
+    pub async fn send_raw(&self, raw: &str) {
+        let streams = std::mem::take(&mut *self.state.streams.lock().unwrap());
+        let mut alive = Vec::with_capacity(streams.len());
+
+        for mut stream in streams {
+            if stream.send_data(Bytes::from(raw.to_owned())).await.is_ok() {
+                alive.push(stream);
+            }
+        }
+
+        self.state.streams.lock().unwrap().extend(alive);
+    }
+
+    pub async fn send_event(&self, event_type: &str) {
+        self.send_raw(&format!(
+            "event: message\ndata: {{\"type\":\"{event_type}\"}}\n\n"
+        ))
+        .await;
+    }
+
+    /// Answers watch requests with the given status and body instead of a stream.
+    pub fn set_watch_response(&self, response: FakeResponse) {
+        *self.state.watch_response.lock().unwrap() = Some(response);
+    }
+
+    /// Answers the next secrets download with `response` instead of the secrets.
+    pub fn queue_download_response(&self, response: FakeResponse) {
+        self.state
+            .download_responses
+            .lock()
+            .unwrap()
+            .push_back(response);
+    }
+
+    pub fn clear_watch_response(&self) {
+        *self.state.watch_response.lock().unwrap() = None;
+    }
+
+    /// Ends every open watch stream.
+    pub fn close_streams(&self) {
+        self.state.streams.lock().unwrap().clear();
+    }
+}
+
+impl Drop for FakeDoppler {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn doppler_response(state: &Arc<DopplerState>, request: &hyper::Request<Body>) -> Response<Body> {
+    match request.uri().path() {
+        "/v3/configs/config/secrets/download" => {
+            state.downloads.fetch_add(1, Ordering::SeqCst);
+
+            if let Some(response) = state.download_responses.lock().unwrap().pop_front() {
+                return response.into_response();
+            }
+
+            let secrets = Value::Object(state.secrets.lock().unwrap().clone());
+            Response::new(Body::from(secrets.to_string()))
+        }
+        "/v3/configs/config/secrets/watch" => {
+            state.watch_connections.fetch_add(1, Ordering::SeqCst);
+            state.last_event_ids.lock().unwrap().push(
+                request
+                    .headers()
+                    .get("Last-Event-ID")
+                    .and_then(|id| id.to_str().ok())
+                    .map(|id| id.to_owned()),
+            );
+
+            if let Some(response) = state.watch_response.lock().unwrap().clone() {
+                return response.into_response();
+            }
+
+            let (mut sender, body) = Body::channel();
+            let state = state.clone();
+            tokio::spawn(async move {
+                let connected = "event: message\ndata: {\"type\":\"connected\"}\n\n";
+                if sender.send_data(Bytes::from(connected)).await.is_ok() {
+                    state.streams.lock().unwrap().push(sender);
+                }
+            });
+
+            Response::new(body)
+        }
+        _ => Response::builder()
+            .status(404)
+            .body(Body::from(
+                r#"{"messages": ["Not found"], "success": false}"#,
+            ))
+            .unwrap(),
+    }
+}
+
+/// Local HTTP receiver standing in for a chat webhook. Records every JSON
+/// body posted to it and answers with the queued statuses, then 200.
+pub struct FakeWebhook {
+    addr: SocketAddr,
+    bodies: Arc<Mutex<Vec<Value>>>,
+    statuses: Arc<Mutex<VecDeque<u16>>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl FakeWebhook {
+    pub async fn start() -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Cannot bind fake webhook");
+        let addr = listener.local_addr().unwrap();
+
+        let bodies = Arc::new(Mutex::new(vec![]));
+        let statuses = Arc::new(Mutex::new(VecDeque::new()));
+
+        let task = {
+            let bodies = bodies.clone();
+            let statuses = statuses.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let bodies = bodies.clone();
+                    let statuses = statuses.clone();
+                    let service = service_fn(move |request: hyper::Request<Body>| {
+                        let bodies = bodies.clone();
+                        let statuses = statuses.clone();
+                        async move {
+                            let body = hyper::body::to_bytes(request.into_body())
+                                .await
+                                .unwrap_or_default();
+                            bodies
+                                .lock()
+                                .unwrap()
+                                .push(serde_json::from_slice(&body).unwrap_or(Value::Null));
+
+                            let status = statuses.lock().unwrap().pop_front().unwrap_or(200);
+                            Ok::<_, Infallible>(FakeResponse::json(status, "ok").into_response())
+                        }
+                    });
+
+                    tokio::spawn(
+                        hyper::server::conn::Http::new().serve_connection(stream, service),
+                    );
+                }
+            })
+        };
+
+        Self {
+            addr,
+            bodies,
+            statuses,
+            task,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}/hook", self.addr)
+    }
+
+    /// Answers the next delivery with `status`.
+    pub fn queue_status(&self, status: u16) {
+        self.statuses.lock().unwrap().push_back(status);
+    }
+
+    /// Every body received, in order, failed deliveries included.
+    pub fn bodies(&self) -> Vec<Value> {
+        self.bodies.lock().unwrap().clone()
+    }
+}
+
+impl Drop for FakeWebhook {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// An audit log under a fresh temporary path.
+pub fn temp_auditor(max_bytes: Option<u64>, max_files: Option<u32>) -> Auditor {
+    let path =
+        std::env::temp_dir().join(format!("doppler-swarm-audit-{}.jsonl", fastrand::u64(..)));
+
+    Auditor::new(&config::AuditLog {
+        path: path.to_string_lossy().into_owned(),
+        max_bytes,
+        max_files,
+    })
+    .unwrap()
+}
+
+/// Polls `condition` until it holds, failing the test after 5 seconds.
+pub async fn wait_until(what: &str, condition: impl Fn() -> bool) {
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+
+    while !condition() {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "Timed out waiting for {what}"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
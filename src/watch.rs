@@ -1,4 +1,5 @@
-use bytes::Bytes;
+use std::time::Duration;
+
 use serde::Deserialize;
 
 #[derive(Debug, Clone, Deserialize)]
@@ -15,19 +16,400 @@ pub enum WatchEvent {
     Connected,
     #[serde(rename = "secrets.update")]
     SecretsUpdate,
+    /// An event type this version doesn't know; skipped rather than
+    /// treated as a broken stream.
+    #[serde(other)]
+    Unknown,
+}
+
+/// A dispatched Server-Sent Event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SseEvent {
+    /// Event type, `message` unless the stream set one.
+    pub event: String,
+    pub data: String,
+    /// Last event id seen on the stream at dispatch time.
+    pub id: Option<String>,
 }
 
-pub fn parse_watch_event(raw_payload: &Bytes) -> crate::result::Result<WatchEvent> {
-    if !raw_payload.starts_with(b"event: message\ndata:") {
-        return Err("invalid event".into());
+/// Incremental Server-Sent Events decoder following the WHATWG
+/// `text/event-stream` parsing rules. Bytes can be pushed in chunks split at
+/// arbitrary boundaries, including in the middle of a line or a CRLF.
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    line: Vec<u8>,
+    started: bool,
+    after_cr: bool,
+    event_type: String,
+    data: String,
+    last_event_id: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl SseDecoder {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    let event: EventPayload = serde_json::from_slice(&raw_payload[20..]).map_err(|e| {
-        format!(
-            "Failed to parse event payload: {}. Payload: {:?}",
-            e, raw_payload
-        )
+    /// Keeps the last event id of the previous stream after a reconnect,
+    /// until the server sets or clears it.
+    pub fn resuming(mut self, last_event_id: Option<String>) -> Self {
+        self.last_event_id = last_event_id;
+        self
+    }
+
+    /// The last `id:` seen on the stream, to send as `Last-Event-ID` on reconnect.
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_event_id.as_deref()
+    }
+
+    /// Reconnection time requested by the server with `retry:`.
+    pub fn retry(&self) -> Option<Duration> {
+        self.retry
+    }
+
+    /// Feeds a chunk of the stream and returns the events it completed.
+    pub fn push(&mut self, mut chunk: &[u8]) -> Vec<SseEvent> {
+        if !self.started {
+            // Wait for enough bytes to tell whether the stream starts with a BOM.
+            let mut head = std::mem::take(&mut self.line);
+            head.extend_from_slice(chunk);
+            if head.len() < 3 && b"\xEF\xBB\xBF".starts_with(&head) {
+                self.line = head;
+                return vec![];
+            }
+
+            self.started = true;
+            let head = head.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(&head).to_vec();
+            return self.push_lines(&head);
+        }
+
+        if self.after_cr && !chunk.is_empty() {
+            self.after_cr = false;
+            chunk = chunk.strip_prefix(b"\n").unwrap_or(chunk);
+        }
+
+        self.push_lines(chunk)
+    }
+
+    fn push_lines(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        let mut events = vec![];
+        let mut rest = chunk;
+
+        while let Some(end) = rest.iter().position(|&b| b == b'\n' || b == b'\r') {
+            self.line.extend_from_slice(&rest[..end]);
+            let line = std::mem::take(&mut self.line);
+
+            if let Some(event) = self.process_line(&line) {
+                events.push(event);
+            }
+
+            if rest[end] == b'\r' {
+                match rest.get(end + 1) {
+                    Some(b'\n') => rest = &rest[end + 2..],
+                    Some(_) => rest = &rest[end + 1..],
+                    None => {
+                        // The LF of a CRLF may arrive with the next chunk.
+                        self.after_cr = true;
+                        rest = &[];
+                    }
+                }
+            } else {
+                rest = &rest[end + 1..];
+            }
+        }
+
+        self.line.extend_from_slice(rest);
+        events
+    }
+
+    fn process_line(&mut self, line: &[u8]) -> Option<SseEvent> {
+        let line = String::from_utf8_lossy(line);
+
+        if line.is_empty() {
+            return self.dispatch();
+        }
+
+        if line.starts_with(':') {
+            return None;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line.as_ref(), ""),
+        };
+
+        match field {
+            "event" => self.event_type = value.to_owned(),
+            "data" => {
+                self.data.push_str(value);
+                self.data.push('\n');
+            }
+            // An empty id resets it.
+            "id" if !value.contains('\0') => {
+                self.last_event_id = (!value.is_empty()).then(|| value.to_owned());
+            }
+            "retry" if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+                if let Ok(millis) = value.parse() {
+                    self.retry = Some(Duration::from_millis(millis));
+                }
+            }
+            _ => {}
+        }
+
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event_type = std::mem::take(&mut self.event_type);
+        let mut data = std::mem::take(&mut self.data);
+
+        if data.is_empty() {
+            return None;
+        }
+
+        data.pop();
+
+        Some(SseEvent {
+            event: if event_type.is_empty() {
+                "message".to_owned()
+            } else {
+                event_type
+            },
+            data,
+            id: self.last_event_id.clone(),
+        })
+    }
+}
+
+pub fn parse_watch_event(event: &SseEvent) -> crate::result::Result<WatchEvent> {
+    let payload: EventPayload = serde_json::from_str(&event.data).map_err(|e| {
+        crate::error::Error::doppler_stream(format!(
+            "Failed to parse event payload {:?}",
+            event.data
+        ))
+        .with_source(e)
     })?;
 
-    Ok(event.event_type)
+    Ok(payload.event_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn event(event: &str, data: &str, id: Option<&str>) -> SseEvent {
+        SseEvent {
+            event: event.to_owned(),
+            data: data.to_owned(),
+            id: id.map(|id| id.to_owned()),
+        }
+    }
+
+    fn decode_chunks(chunks: &[&[u8]]) -> Vec<SseEvent> {
+        let mut decoder = SseDecoder::new();
+        chunks
+            .iter()
+            .flat_map(|chunk| decoder.push(chunk))
+            .collect()
+    }
+
+    #[test]
+    fn test_doppler_event() {
+        let events = decode_chunks(&[b"event: message\ndata: {\"type\":\"secrets.update\"}\n\n"]);
+
+        assert_eq!(
+            events,
+            vec![event("message", r#"{"type":"secrets.update"}"#, None)]
+        );
+        assert_eq!(
+            parse_watch_event(&events[0]).unwrap(),
+            WatchEvent::SecretsUpdate
+        );
+    }
+
+    #[test]
+    fn test_multiple_events_in_one_chunk() {
+        let events =
+            decode_chunks(&[b"data: {\"type\":\"connected\"}\n\ndata: {\"type\":\"ping\"}\n\n"]);
+
+        assert_eq!(
+            events
+                .iter()
+                .map(|e| parse_watch_event(e).unwrap())
+                .collect::<Vec<_>>(),
+            vec![WatchEvent::Connected, WatchEvent::Ping]
+        );
+    }
+
+    #[test]
+    fn test_event_split_across_chunks() {
+        let events = decode_chunks(&[
+            b"event: mess",
+            b"age\ndata: {\"type\"",
+            b":\"ping\"}\n",
+            b"\n",
+        ]);
+
+        assert_eq!(events, vec![event("message", r#"{"type":"ping"}"#, None)]);
+    }
+
+    #[test]
+    fn test_crlf_split_between_chunks() {
+        let events = decode_chunks(&[b"data: a\r", b"\n\r", b"\ndata: b\r\r"]);
+
+        assert_eq!(
+            events,
+            vec![event("message", "a", None), event("message", "b", None)]
+        );
+    }
+
+    #[test]
+    fn test_comments_multiline_data_and_fields() {
+        let events = decode_chunks(&[
+            b": keep-alive\nretry: 3000\nid: 7\nevent: update\ndata: line1\ndata:line2\ndata\nfoo: bar\n\n",
+        ]);
+
+        assert_eq!(events, vec![event("update", "line1\nline2\n", Some("7"))]);
+    }
+
+    #[test]
+    fn test_retry_and_last_event_id() {
+        let mut decoder = SseDecoder::new();
+        assert_eq!(decoder.retry(), None);
+        assert_eq!(decoder.last_event_id(), None);
+
+        decoder.push(b"retry: 2500\nid: 41\n\n");
+        assert_eq!(decoder.retry(), Some(Duration::from_millis(2500)));
+        assert_eq!(decoder.last_event_id(), Some("41"));
+
+        // Invalid retry values are ignored.
+        decoder.push(b"retry: 1s\nretry:\nretry: -5\nid: 42\n\n");
+        assert_eq!(decoder.retry(), Some(Duration::from_millis(2500)));
+        assert_eq!(decoder.last_event_id(), Some("42"));
+
+        assert_eq!(
+            decoder.push(b"id:\ndata: x\n\n"),
+            vec![event("message", "x", None)]
+        );
+        assert_eq!(decoder.last_event_id(), None);
+    }
+
+    #[test]
+    fn test_event_without_data_is_not_dispatched() {
+        let events = decode_chunks(&[b"event: update\nid: 1\n\ndata: x\n\n"]);
+
+        // The id persists, the event type is reset.
+        assert_eq!(events, vec![event("message", "x", Some("1"))]);
+    }
+
+    #[test]
+    fn test_leading_bom_is_stripped() {
+        let events = decode_chunks(&[b"\xEF", b"\xBB", b"\xBFdata: x\n\n"]);
+
+        assert_eq!(events, vec![event("message", "x", None)]);
+    }
+
+    #[test]
+    fn test_incomplete_event_is_kept() {
+        let mut decoder = SseDecoder::new();
+
+        assert!(decoder.push(b"data: x\n").is_empty());
+        assert_eq!(decoder.push(b"\n"), vec![event("message", "x", None)]);
+    }
+
+    #[test]
+    fn test_unknown_event_type() {
+        let result = parse_watch_event(&event("message", r#"{"type":"secrets.rotated"}"#, None));
+
+        assert_eq!(result.unwrap(), WatchEvent::Unknown);
+    }
+
+    #[test]
+    fn test_invalid_payload() {
+        let result = parse_watch_event(&event("message", "nope", None));
+
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .starts_with("Failed to parse event payload"));
+    }
+
+    fn arb_event() -> impl Strategy<Value = SseEvent> {
+        (
+            prop::option::of("[a-z.]{1,12}"),
+            prop::collection::vec("[ -~]{0,20}", 1..4),
+            prop::option::of("[0-9a-f]{1,8}"),
+        )
+            .prop_map(|(event_type, lines, id)| SseEvent {
+                event: event_type.unwrap_or_else(|| "message".to_owned()),
+                data: lines.join("\n"),
+                id,
+            })
+    }
+
+    fn encode(events: &[SseEvent], newline: &str, comments: bool) -> Vec<u8> {
+        let mut out = String::new();
+        let mut last_id: Option<&String> = None;
+
+        for event in events {
+            if comments {
+                out.push_str(&format!(": comment{newline}"));
+            }
+            if event.event != "message" {
+                out.push_str(&format!("event: {}{newline}", event.event));
+            }
+            if event.id.as_ref() != last_id {
+                if let Some(id) = &event.id {
+                    out.push_str(&format!("id: {id}{newline}"));
+                }
+            }
+            for line in event.data.split('\n') {
+                out.push_str(&format!("data: {line}{newline}"));
+            }
+            out.push_str(newline);
+            if event.id.is_some() {
+                last_id = event.id.as_ref();
+            }
+        }
+
+        out.into_bytes()
+    }
+
+    proptest! {
+        #[test]
+        fn prop_chunk_boundaries_do_not_matter(
+            events in prop::collection::vec(arb_event(), 1..6),
+            newline in prop::sample::select(vec!["\n", "\r\n", "\r"]),
+            comments in any::<bool>(),
+            cuts in prop::collection::vec(any::<prop::sample::Index>(), 0..10),
+        ) {
+            // An event without an id inherits the last one seen on the stream.
+            let mut expected = events.clone();
+            let mut last_id = None;
+            for event in &mut expected {
+                if event.id.is_some() {
+                    last_id = event.id.clone();
+                }
+                event.id = last_id.clone();
+            }
+
+            let raw = encode(&events, newline, comments);
+
+            let mut cuts: Vec<usize> = cuts.iter().map(|cut| cut.index(raw.len() + 1)).collect();
+            cuts.sort();
+            cuts.dedup();
+
+            let mut decoder = SseDecoder::new();
+            let mut decoded = vec![];
+            let mut start = 0;
+            for cut in cuts.into_iter().chain([raw.len()]) {
+                decoded.extend(decoder.push(&raw[start..cut]));
+                start = cut;
+            }
+
+            prop_assert_eq!(decoded, expected);
+        }
+    }
 }
@@ -1,101 +1,246 @@
-use std::collections::HashMap;
+use std::time::Duration;
 
 use crate::{
+    backoff::{Backoff, CircuitState},
     config,
-    secrets::fetch_secrets,
-    watch::{parse_watch_event, WatchEvent},
+    error::Error,
+    metrics::Metrics,
+    reconciler::SyncRequest,
+    secrets::{rate_limit_delay, DopplerError},
+    status::Status,
+    watch::{parse_watch_event, SseDecoder, WatchEvent},
 };
-use bytes::Bytes;
 use futures::StreamExt;
-use tokio::time::timeout;
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time::timeout,
+};
+
+// Doppler sends ping event every 30 seconds.
+// If we don't receive any events for 60 seconds, we assume that the connection is dead.
+const WATCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
 
-#[derive(Debug, Clone)]
+/// Consecutive watch failures after which the circuit breaker opens.
+const BREAKER_THRESHOLD: u32 = 10;
+
+/// Follows a watcher's Doppler watch stream. Syncing happens in the
+/// watcher's `Reconciler`; the stream only signals it, so pings keep being
+/// read while a slow service update runs.
+#[derive(Debug)]
 pub struct Worker {
     watcher: config::Watcher,
     http: reqwest::Client,
     stop: tokio::sync::watch::Receiver<bool>,
     wanna_stop: bool,
-}
-
-pub fn should_update_docker_service(
-    doppler_secrets: &HashMap<String, String>,
-    docker_secrets: &HashMap<String, String>,
-) -> bool {
-    doppler_secrets != docker_secrets
+    watch_timeout: Duration,
+    /// Reconnect delays for the watch stream. The server can change the
+    /// base delay with an SSE `retry:` field.
+    backoff: Backoff,
+    /// Last SSE event id seen, sent as `Last-Event-ID` when reconnecting.
+    last_event_id: Option<String>,
+    syncs: mpsc::Sender<SyncRequest>,
+    status: Status,
+    metrics: Metrics,
 }
 
 impl Worker {
-    pub fn new(watcher: config::Watcher, stop: tokio::sync::watch::Receiver<bool>) -> Self {
+    pub fn new(
+        watcher: config::Watcher,
+        stop: tokio::sync::watch::Receiver<bool>,
+        syncs: mpsc::Sender<SyncRequest>,
+    ) -> Self {
         let http = reqwest::ClientBuilder::new()
             .use_rustls_tls()
             .connect_timeout(std::time::Duration::from_secs(10))
             .build()
             .expect("Cannot build http client");
 
+        // Shutdown may have been requested before this worker was created.
+        let wanna_stop = *stop.borrow();
+
         Self {
             watcher,
             http,
             stop,
-            wanna_stop: false,
+            wanna_stop,
+            watch_timeout: WATCH_TIMEOUT,
+            backoff: Backoff::new(RECONNECT_DELAY, MAX_RECONNECT_DELAY, BREAKER_THRESHOLD),
+            last_event_id: None,
+            syncs,
+            status: Status::default(),
+            metrics: Metrics::default(),
         }
     }
 
-    pub async fn run(&mut self) {
+    pub fn report_status(&mut self, status: Status) {
+        self.status = status;
+    }
+
+    pub fn report_metrics(&mut self, metrics: Metrics) {
+        self.metrics = metrics;
+    }
+
+    pub fn name(&self) -> &str {
+        &self.watcher.name
+    }
+
+    /// Follows the watch stream until `stop` flips to true. Returns the
+    /// error that made it give up for good, e.g. a revoked token.
+    pub async fn run(&mut self) -> crate::result::Result<()> {
         while !self.wanna_stop {
-            if let Err(e) = self.watch_for_updates().await {
-                log::warn!("{e}");
-                if !self.wanna_stop {
-                    tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            let result = self.watch_for_updates().await;
+            self.status.stream_disconnected(&self.watcher.name);
+
+            let Err(e) = result else {
+                continue;
+            };
+            self.metrics.watch_failed(&self.watcher.name);
+
+            if !e.is_retryable() {
+                self.backoff.halt();
+                self.report_circuit();
+                log::error!(
+                    watcher = self.watcher.name.as_str();
+                    "{}. Circuit breaker {}, not retrying until restart",
+                    e,
+                    self.backoff.state()
+                );
+                return Err(e);
+            }
+
+            log::warn!(watcher = self.watcher.name.as_str(); "{}", e);
+
+            // A rate limit counts as a failure too, so a `Retry-After: 0`
+            // can't make us reconnect in a tight loop.
+            let delay = self.backoff.next_delay();
+            self.report_circuit();
+            if self.backoff.failures() == BREAKER_THRESHOLD {
+                log::error!(
+                    watcher = self.watcher.name.as_str();
+                    "Circuit breaker {} after {} consecutive watch failures",
+                    self.backoff.state(),
+                    BREAKER_THRESHOLD
+                );
+            }
+
+            match e.retry_after() {
+                Some(retry_after) if retry_after > delay => {
+                    log::info!(
+                        watcher = self.watcher.name.as_str();
+                        "Reconnecting in {:.1}s as requested by Doppler",
+                        retry_after.as_secs_f64()
+                    );
+                    self.sleep_unless_stopped(retry_after).await;
+                }
+                _ => {
+                    log::info!(
+                        watcher = self.watcher.name.as_str();
+                        "Reconnecting in {:.1}s (failure {}, circuit breaker {})",
+                        delay.as_secs_f64(),
+                        self.backoff.failures(),
+                        self.backoff.state()
+                    );
+                    self.sleep_unless_stopped(delay).await;
                 }
             }
         }
+
+        Ok(())
     }
 
-    pub async fn sync_secrets(&self) -> crate::result::Result<()> {
-        let doppler_secrets = fetch_secrets(&self.http, &self.watcher.doppler_token)
-            .await
-            .map_err(|e| format!("Failed to fetch secrets: {}", e))?;
+    async fn sleep_unless_stopped(&mut self, delay: Duration) {
+        if self.wanna_stop {
+            return;
+        }
 
-        let services = crate::docker::list_services(&self.watcher)
-            .await
-            .map_err(|e| format!("Failed to list services: {}", e))?;
-
-        for service in services {
-            let docker_secrets = crate::docker::get_current_env_vars(&service)
-                .await
-                .map_err(|e| format!("[{}] Failed to get current env vars: {}", service, e))?;
-
-            if should_update_docker_service(&doppler_secrets, &docker_secrets) {
-                log::info!("[{}] [{}] Updating service...", &self.watcher.name, service);
-
-                crate::docker::update_service(
-                    &service,
-                    docker_secrets.clone(),
-                    doppler_secrets.clone(),
-                )
-                .await
-                .map_err(|e| format!("[{}] Failed to update docker service: {}", service, e))?;
-
-                log::info!("[{}] [{}] Service updated", &self.watcher.name, service);
-            } else {
-                log::info!("[{}] [{}] No changes detected", &self.watcher.name, service);
+        tokio::select! {
+            _ = self.stop.changed() => {
+                self.wanna_stop = *self.stop.borrow();
             }
+            _ = tokio::time::sleep(delay) => {}
         }
+    }
 
-        Ok(())
+    fn connection_healthy(&mut self) {
+        self.status.stream_connected(&self.watcher.name);
+
+        if self.backoff.state() == CircuitState::Open {
+            log::info!(watcher = self.watcher.name.as_str(); "Circuit breaker closed");
+        }
+
+        self.backoff.reset();
+        self.report_circuit();
     }
 
-    pub async fn watch_for_updates(&mut self) -> crate::result::Result<()> {
-        let response = self
+    fn report_circuit(&self) {
+        self.status
+            .circuit_breaker(&self.watcher.name, self.backoff.state());
+    }
+
+    /// Hands a `secrets.update` to the reconciler without waiting for the
+    /// sync. A full queue already holds a sync that will fetch the latest
+    /// secrets, so the signal can be dropped.
+    fn request_sync(&self, sync_id: String) {
+        match self.syncs.try_send(SyncRequest::SecretsUpdate { sync_id }) {
+            Ok(()) | Err(TrySendError::Full(_)) => {}
+            Err(TrySendError::Closed(_)) => {
+                log::warn!(
+                    watcher = self.watcher.name.as_str();
+                    "Reconciler stopped, ignoring secrets update"
+                );
+            }
+        }
+    }
+
+    fn remember_stream_position(&mut self, decoder: &SseDecoder) {
+        self.last_event_id = decoder.last_event_id().map(str::to_owned);
+
+        if let Some(retry) = decoder.retry() {
+            self.backoff.set_base(retry);
+        }
+    }
+
+    async fn watch_for_updates(&mut self) -> crate::result::Result<()> {
+        let mut request = self
             .http
-            .get("https://api.doppler.com/v3/configs/config/secrets/watch?include_dynamic_secrets=false&include_managed_secrets=false")
-            .bearer_auth(&self.watcher.doppler_token)
+            .get(format!(
+                "{}/v3/configs/config/secrets/watch?include_dynamic_secrets=false&include_managed_secrets=false",
+                self.watcher.api_url()
+            ))
+            .bearer_auth(&self.watcher.doppler_token);
+
+        if let Some(last_event_id) = &self.last_event_id {
+            request = request.header("Last-Event-ID", last_event_id);
+        }
+
+        let response = request
             .send()
             .await
-            .map_err(|e| format!("[{}] Failed to watch for updates: {}", &self.watcher.name, e))?;
+            .map_err(|e| Error::doppler_http("Failed to watch for updates", e))?;
+
+        if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Err(Error::DopplerRateLimited {
+                message: "Failed to watch for updates: rate limited by Doppler".to_owned(),
+                retry_after: rate_limit_delay(response.headers(), std::time::SystemTime::now()),
+            });
+        }
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.bytes().await.unwrap_or_default();
+            return Err(Error::doppler(
+                "Failed to watch for updates",
+                DopplerError::from_watch_response(status, &body),
+            ));
+        }
+
+        self.metrics.watch_connected(&self.watcher.name);
 
         let mut stream = response.bytes_stream();
-        let mut buf: Vec<u8> = Vec::with_capacity(1024);
+        let mut decoder = SseDecoder::new().resuming(self.last_event_id.clone());
 
         loop {
             tokio::select! {
@@ -103,47 +248,63 @@ impl Worker {
                     self.wanna_stop = *self.stop.borrow();
                     return Ok(());
                 }
-                // Doppler sends ping event every 30 seconds.
-                // If we don't receive any events for 60 seconds, we assume that the connection is dead.
-                resp = timeout(std::time::Duration::from_secs(60), stream.next()) => {
+                resp = timeout(self.watch_timeout, stream.next()) => {
                     match resp {
-                         Ok(Some(Ok(item))) => {
-                            buf.extend_from_slice(&item);
-                            if !buf.ends_with(b"\n\n") {
-                                continue;
-                            }
+                        Ok(Some(Ok(item))) => {
+                            let events = decoder.push(&item);
+                            self.remember_stream_position(&decoder);
 
-                            let buf_copy: Bytes = Bytes::copy_from_slice(&buf);
-                            buf.clear();
-                            match parse_watch_event(&buf_copy) {
-                                Ok(WatchEvent::SecretsUpdate) => {
-                                    self.sync_secrets().await?;
-                                }
-                                Ok(WatchEvent::Ping) => {
-                                    log::debug!("[{}] Received event: Ping", &self.watcher.name);
-                                }
-                                Ok(WatchEvent::Connected) => {
-                                    log::info!("[{}] Received event: Connected", &self.watcher.name);
-                                }
-                                Err(e) => {
-                                    return Err(e);
+                            for event in events {
+                                self.status.stream_event(&self.watcher.name);
+
+                                let watch_event = parse_watch_event(&event)?;
+                                self.metrics.watch_event(&self.watcher.name, watch_event);
+
+                                match watch_event {
+                                    WatchEvent::SecretsUpdate => {
+                                        let sync_id = crate::logging::new_sync_id();
+                                        crate::logging::log_in_sync(&sync_id, || {
+                                            log::info!(
+                                                watcher = self.watcher.name.as_str(), event = "secrets.update";
+                                                "Received event: SecretsUpdate (id {:?})",
+                                                event.id
+                                            );
+                                        });
+                                        self.request_sync(sync_id);
+                                    }
+                                    WatchEvent::Ping => {
+                                        log::debug!(
+                                            watcher = self.watcher.name.as_str(), event = "ping";
+                                            "Received event: Ping"
+                                        );
+                                    }
+                                    WatchEvent::Connected => {
+                                        log::info!(
+                                            watcher = self.watcher.name.as_str(), event = "connected";
+                                            "Received event: Connected"
+                                        );
+                                        self.connection_healthy();
+                                    }
+                                    WatchEvent::Unknown => {
+                                        log::debug!(
+                                            watcher = self.watcher.name.as_str();
+                                            "Skipping unknown event {:?}",
+                                            event.data
+                                        );
+                                    }
                                 }
                             }
                         }
                         Ok(Some(Err(e))) => {
-                            return Err(format!(
-                                "[{}] Failed to read watch stream: {}",
-                                &self.watcher.name, e
-                            )
-                            .into())
+                            return Err(Error::doppler_stream("Failed to read watch stream")
+                                .with_source(e))
                         }
-                        Ok(None) => return Err("Watch stream ended unexpectedly".into()),
+                        Ok(None) => return Err(Error::doppler_stream("Watch stream ended unexpectedly")),
                         Err(_) => {
-                            return Err(format!(
-                                "[{}] Watch stream timed out after 60 seconds",
-                                &self.watcher.name
-                            )
-                            .into());
+                            return Err(Error::doppler_stream(format!(
+                                "Watch stream timed out after {} seconds",
+                                self.watch_timeout.as_secs()
+                            )));
                         }
                     }
                 }
@@ -151,3 +312,347 @@ impl Worker {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        reconciler::Reconciler,
+        testing::{wait_until, FakeDocker, FakeDoppler, FakeResponse},
+    };
+
+    fn watching_worker(doppler: &FakeDoppler) -> (Worker, tokio::sync::watch::Sender<bool>) {
+        watching_worker_for_url(&doppler.url())
+    }
+
+    fn watching_worker_for_url(url: &str) -> (Worker, tokio::sync::watch::Sender<bool>) {
+        let watcher = config::Watcher {
+            name: "My watcher".to_owned(),
+            docker_services: vec!["backend".to_owned()],
+            doppler_token: "secret".to_owned(),
+            doppler_api_url: Some(url.to_owned()),
+            ..Default::default()
+        };
+        let (tx, rx) = tokio::sync::watch::channel(false);
+        let (syncs, _) = mpsc::channel(1);
+
+        let mut worker = Worker::new(watcher, rx, syncs);
+        worker.watch_timeout = Duration::from_millis(200);
+        worker.backoff = Backoff::new(Duration::from_millis(10), Duration::from_millis(10), 3);
+
+        (worker, tx)
+    }
+
+    #[tokio::test]
+    async fn test_secrets_update_signals_reconciler() {
+        let doppler = FakeDoppler::start(&[]).await;
+        let (mut worker, tx) = watching_worker(&doppler);
+        worker.watch_timeout = Duration::from_secs(60);
+        let (syncs, mut requests) = mpsc::channel(16);
+        worker.syncs = syncs;
+
+        let handle = tokio::spawn(async move { worker.run().await });
+        wait_until("watch stream", || doppler.live_streams() == 1).await;
+
+        doppler.send_event("secrets.update").await;
+        assert!(matches!(
+            requests.recv().await,
+            Some(SyncRequest::SecretsUpdate { .. })
+        ));
+
+        tx.send(true).unwrap();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unknown_event_keeps_stream_open() {
+        let doppler = FakeDoppler::start(&[]).await;
+        let (mut worker, tx) = watching_worker(&doppler);
+        worker.watch_timeout = Duration::from_secs(60);
+        let (syncs, mut requests) = mpsc::channel(16);
+        worker.syncs = syncs;
+
+        let handle = tokio::spawn(async move { worker.run().await });
+        wait_until("watch stream", || doppler.live_streams() == 1).await;
+
+        doppler.send_event("secrets.rotated").await;
+        doppler.send_event("secrets.update").await;
+        assert!(matches!(
+            requests.recv().await,
+            Some(SyncRequest::SecretsUpdate { .. })
+        ));
+        assert_eq!(doppler.watch_connections(), 1);
+
+        tx.send(true).unwrap();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stream_is_followed_during_slow_service_update() {
+        let doppler = FakeDoppler::start(&[("API_KEY", "v1")]).await;
+        // A rolling update that takes far longer than the watch timeout.
+        let docker = FakeDocker::start(|request| {
+            let service = r#"{"ID": "abc123", "Version": {"Index": 1},
+                "Spec": {"Name": "backend", "TaskTemplate": {"ContainerSpec": {"Env": []}}}}"#;
+
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/services") => FakeResponse::json(200, format!("[{service}]")),
+                ("GET", _) => FakeResponse::json(200, service),
+                _ => FakeResponse::json(200, "{}").delayed(Duration::from_secs(3600)),
+            }
+        });
+
+        let (mut worker, tx) = watching_worker(&doppler);
+        let (syncs, requests) = mpsc::channel(16);
+        worker.syncs = syncs;
+        let watcher = config::Watcher {
+            debounce_seconds: Some(0),
+            ..worker.watcher.clone()
+        };
+        let mut reconciler = Reconciler::new(watcher, docker.engine(), tx.subscribe(), requests);
+
+        let reconciler = tokio::spawn(async move { reconciler.run().await });
+        let handle = tokio::spawn(async move { worker.run().await });
+        wait_until("watch stream", || doppler.live_streams() == 1).await;
+
+        doppler.send_event("secrets.update").await;
+        wait_until("service update", || {
+            docker.requests().iter().any(|r| r.method == "POST")
+        })
+        .await;
+
+        // The stream keeps being read, and reconnects, while the update runs.
+        doppler.close_streams();
+        wait_until("reconnect", || doppler.watch_connections() == 2).await;
+
+        tx.send(true).unwrap();
+        handle.await.unwrap().unwrap();
+        reconciler.abort();
+    }
+
+    #[tokio::test]
+    async fn test_run_reconnects_after_stream_timeout() {
+        let doppler = FakeDoppler::start(&[]).await;
+        let (mut worker, tx) = watching_worker(&doppler);
+
+        let handle = tokio::spawn(async move { worker.run().await });
+
+        // The fake only sends "connected" and then stays silent.
+        wait_until("reconnect", || doppler.watch_connections() >= 2).await;
+
+        tx.send(true).unwrap();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_reconnects_after_stream_end() {
+        let doppler = FakeDoppler::start(&[]).await;
+        let (mut worker, tx) = watching_worker(&doppler);
+        worker.watch_timeout = Duration::from_secs(60);
+
+        let handle = tokio::spawn(async move { worker.run().await });
+
+        wait_until("watch stream", || doppler.live_streams() == 1).await;
+        doppler.send_event("ping").await;
+        doppler.close_streams();
+
+        wait_until("reconnect", || doppler.watch_connections() == 2).await;
+
+        tx.send(true).unwrap();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_reconnects_with_last_event_id_and_server_retry() {
+        let doppler = FakeDoppler::start(&[]).await;
+        let (mut worker, tx) = watching_worker(&doppler);
+        worker.watch_timeout = Duration::from_secs(60);
+        // Only the server's retry hint makes the reconnect happen in time.
+        worker.backoff = Backoff::new(Duration::from_secs(3600), Duration::from_secs(3600), 3);
+
+        let handle = tokio::spawn(async move { worker.run().await });
+
+        wait_until("watch stream", || doppler.live_streams() == 1).await;
+        doppler
+            .send_raw("retry: 10\nid: 42\ndata: {\"type\":\"ping\"}\n\n")
+            .await;
+        doppler.close_streams();
+
+        wait_until("reconnect", || doppler.watch_connections() == 2).await;
+        assert_eq!(doppler.last_event_ids(), vec![None, Some("42".to_owned())]);
+
+        // An empty id clears it, so no header is sent.
+        wait_until("watch stream", || doppler.live_streams() == 1).await;
+        doppler.send_raw("id:\ndata: {\"type\":\"ping\"}\n\n").await;
+        doppler.close_streams();
+
+        wait_until("reconnect", || doppler.watch_connections() == 3).await;
+        assert_eq!(
+            doppler.last_event_ids(),
+            vec![None, Some("42".to_owned()), None]
+        );
+
+        tx.send(true).unwrap();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_does_not_spin_on_zero_server_retry() {
+        let doppler = FakeDoppler::start(&[]).await;
+        let (mut worker, tx) = watching_worker(&doppler);
+        worker.watch_timeout = Duration::from_secs(60);
+        worker.backoff = Backoff::new(Duration::from_secs(3600), Duration::from_secs(3600), 3);
+
+        let handle = tokio::spawn(async move { worker.run().await });
+
+        wait_until("watch stream", || doppler.live_streams() == 1).await;
+        doppler
+            .send_raw("retry: 0\ndata: {\"type\":\"ping\"}\n\n")
+            .await;
+        doppler.set_watch_response(FakeResponse::json(500, "{}"));
+        doppler.close_streams();
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(doppler.watch_connections() < 10);
+
+        tx.send(true).unwrap();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_waits_for_retry_after_when_rate_limited() {
+        let doppler = FakeDoppler::start(&[]).await;
+        doppler.set_watch_response(FakeResponse::json(429, "{}").header("Retry-After", "1"));
+        let (mut worker, tx) = watching_worker(&doppler);
+        worker.backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(5), 10);
+
+        let started = tokio::time::Instant::now();
+        let handle = tokio::spawn(async move {
+            worker.run().await.unwrap();
+            worker
+        });
+
+        wait_until("reconnect", || doppler.watch_connections() >= 2).await;
+        assert!(started.elapsed() >= Duration::from_secs(1));
+
+        tx.send(true).unwrap();
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_backs_off_when_rate_limited_without_delay() {
+        let doppler = FakeDoppler::start(&[]).await;
+        doppler.set_watch_response(FakeResponse::json(429, "{}").header("Retry-After", "0"));
+        let (mut worker, tx) = watching_worker(&doppler);
+        // The backoff, not Doppler's zero Retry-After, decides the delay.
+        worker.backoff = Backoff::new(Duration::from_secs(3600), Duration::from_secs(3600), 3);
+
+        let handle = tokio::spawn(async move {
+            worker.run().await.unwrap();
+            worker
+        });
+
+        wait_until("first attempt", || doppler.watch_connections() == 1).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        tx.send(true).unwrap();
+        let worker = handle.await.unwrap();
+        assert_eq!(worker.backoff.failures(), 1);
+        assert_eq!(doppler.watch_connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_stops_on_invalid_token() {
+        let doppler = FakeDoppler::start(&[]).await;
+        doppler.set_watch_response(FakeResponse::json(
+            401,
+            r#"{"messages": ["Invalid Auth token"], "success": false}"#,
+        ));
+        let (mut worker, _tx) = watching_worker(&doppler);
+
+        let error = tokio::time::timeout(Duration::from_secs(5), worker.run())
+            .await
+            .expect("Worker kept retrying")
+            .unwrap_err();
+
+        assert!(matches!(error, Error::DopplerAuth { .. }));
+
+        assert_eq!(doppler.watch_connections(), 1);
+        assert_eq!(worker.backoff.state(), CircuitState::Halted);
+    }
+
+    #[tokio::test]
+    async fn test_watch_classifies_error_statuses() {
+        let doppler = FakeDoppler::start(&[]).await;
+        let (mut worker, _tx) = watching_worker(&doppler);
+
+        doppler.set_watch_response(FakeResponse::json(
+            403,
+            r#"{"messages": ["Live updates are not available on your plan"], "success": false}"#,
+        ));
+        let e = worker.watch_for_updates().await.unwrap_err();
+        assert!(matches!(e, Error::DopplerAuth { .. }));
+        assert!(!e.is_retryable());
+        assert_eq!(
+            e.to_string(),
+            "Failed to watch for updates: Doppler plan does not support watching secrets \
+             (Team or Enterprise plan required): Live updates are not available on your plan"
+        );
+
+        doppler.set_watch_response(FakeResponse::json(503, ""));
+        let e = worker.watch_for_updates().await.unwrap_err();
+        assert!(matches!(e, Error::DopplerHttp { .. }));
+        assert!(e.is_retryable());
+        assert_eq!(
+            e.to_string(),
+            "Failed to watch for updates: Doppler server error 503 Service Unavailable: Service Unavailable"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_opens_circuit_and_closes_on_healthy_connection() {
+        let doppler = FakeDoppler::start(&[]).await;
+        doppler.set_watch_response(FakeResponse::json(500, "oops"));
+        let (mut worker, tx) = watching_worker(&doppler);
+        worker.backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(5), 3);
+        let status = Status::default();
+        status.register("My watcher");
+        worker.report_status(status.clone());
+
+        let handle = tokio::spawn(async move {
+            worker.run().await.unwrap();
+            worker
+        });
+
+        let circuit = |state| {
+            let status = status.clone();
+            move || status.report().watchers["My watcher"].circuit_breaker == state
+        };
+
+        wait_until("circuit open", circuit(CircuitState::Open)).await;
+        doppler.clear_watch_response();
+        wait_until("circuit closed", circuit(CircuitState::Closed)).await;
+
+        tx.send(true).unwrap();
+        let worker = handle.await.unwrap();
+        assert_eq!(worker.backoff.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_run_stops_during_backoff() {
+        let doppler = FakeDoppler::start(&[]).await;
+        doppler.set_watch_response(FakeResponse::json(500, "oops"));
+        let (mut worker, tx) = watching_worker(&doppler);
+        worker.backoff = Backoff::new(Duration::from_secs(3600), Duration::from_secs(3600), 3);
+
+        let handle = tokio::spawn(async move { worker.run().await });
+        wait_until("first attempt", || doppler.watch_connections() == 1).await;
+
+        tx.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .expect("Worker did not stop")
+            .unwrap()
+            .unwrap();
+    }
+}